@@ -1,26 +1,109 @@
-use chrono::{DateTime, Utc};
-use web3::transports::Http;
-use web3::types::{Address, BlockId, BlockNumber, U256, U64};
-use web3::Web3;
-
-/// Binary search
-pub async fn get_balance_by_timestamp(
-    web3: &Web3<Http>,
-    account: Address,
+use crate::block_timestamps::BlockTimestamps;
+use crate::config::SeriesInterval;
+use crate::rpc::RetryPolicy;
+use chrono::{DateTime, Datelike, NaiveDate, Utc, Weekday};
+use futures::{stream, StreamExt, TryStreamExt};
+use web3::types::{Address, BlockId, BlockNumber, Bytes, CallRequest, H256, U256, U64};
+use web3::{Transport, Web3};
+
+/// Selector of `balanceOf(address)`
+const BALANCE_OF_SELECTOR: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+
+/// Balances of one account at one block
+pub struct Portfolio {
+    /// Requested timestamp
+    pub timestamp: DateTime<Utc>,
+    pub block_number: U64,
+    pub balance: U256,
+    /// (token, balance). `None` - `balanceOf` failed, e.g. the token wasn't deployed yet
+    pub token_balances: Vec<(Address, Option<U256>)>,
+}
+
+/// Balance of one account at the end of one period of the balance series
+pub struct BalancePoint {
+    /// Last day of the period
+    pub date: NaiveDate,
+    /// Last block before the end of the period
+    pub block_number: U64,
+    pub balance: U256,
+}
+
+/// Result: the first block not earlier than `timestamp`. `None` if there is no block for `timestamp`
+pub async fn find_block_by_timestamp<T: Transport>(
+    web3: &Web3<T>,
+    retry_policy: &RetryPolicy,
+    block_timestamps: &BlockTimestamps,
     timestamp: DateTime<Utc>,
-) -> Result<Option<U256>, Box<dyn std::error::Error>> {
-    let timestamp = timestamp.timestamp();
+) -> Result<Option<U64>, Box<dyn std::error::Error>> {
+    let block_end = retry_policy
+        .call("eth_blockNumber", || web3.eth().block_number())
+        .await?;
 
-    let mut block_start = U64::from(0_u64);
-    let mut block_end = web3.eth().block_number().await?;
-    let mut current_block = (block_end + block_start) / 2_u64;
+    find_block_in_range(
+        web3,
+        retry_policy,
+        block_timestamps,
+        timestamp,
+        U64::zero(),
+        block_end,
+    )
+    .await
+}
+
+/// Binary search in `block_start..=block_end`, narrowed by the known block timestamps.
+/// Timestamps of the checked blocks become known
+/// Result: the first block not earlier than `timestamp`. `None` if there is no block for `timestamp`
+async fn find_block_in_range<T: Transport>(
+    web3: &Web3<T>,
+    retry_policy: &RetryPolicy,
+    block_timestamps: &BlockTimestamps,
+    timestamp: DateTime<Utc>,
+    mut block_start: U64,
+    mut block_end: U64,
+) -> Result<Option<U64>, Box<dyn std::error::Error>> {
+    let timestamp = timestamp.timestamp();
 
     let mut min_diff = u64::MAX;
     let mut min_diff_block = None;
+
+    let (lower, upper) = block_timestamps.get_bounds(timestamp.max(0) as u64)?;
+    if let Some((block_number, _)) = lower {
+        block_start = block_start.max(block_number).min(block_end);
+    }
+    if let Some((block_number, block_timestamp)) = upper.filter(|(v, _)| *v <= block_end) {
+        block_end = block_number;
+        min_diff = (block_timestamp as i64 - timestamp).unsigned_abs();
+        min_diff_block = Some(block_number);
+
+        // The previous block is known to be earlier, so there is nothing to search
+        let is_next = match lower {
+            Some((lower_block, _)) => lower_block + 1 == block_number,
+            None => block_number.is_zero(),
+        };
+        if min_diff == 0 || is_next {
+            debug!(
+                "Block {} of timestamp {} is found in the index.",
+                block_number, timestamp
+            );
+
+            return Ok(min_diff_block);
+        }
+    }
+
+    let mut current_block = (block_end + block_start) / 2_u64;
+
     loop {
         let current_block_number_old = current_block;
 
-        if let Some(diff) = check_block(web3, current_block, timestamp).await? {
+        let diff = check_block(
+            web3,
+            retry_policy,
+            block_timestamps,
+            current_block,
+            timestamp,
+        )
+        .await?;
+        if let Some(diff) = diff {
             if diff < 0 {
                 // If "block timestamp" is to the LEFT of "desired timestamp" - then we need to move RIGHT
 
@@ -28,8 +111,8 @@ pub async fn get_balance_by_timestamp(
             } else {
                 // If "block timestamp" is to the RIGHT of "desired timestamp" - then we need to move LEFT
 
-                if (diff.abs() as u64) < min_diff {
-                    min_diff = diff.abs() as u64;
+                if diff.unsigned_abs() < min_diff {
+                    min_diff = diff.unsigned_abs();
                     min_diff_block = Some(current_block);
                 }
 
@@ -47,18 +130,208 @@ pub async fn get_balance_by_timestamp(
         }
     }
 
-    let balance = if let Some(min_diff_block) = min_diff_block {
-        let balance = web3
-            .eth()
-            .balance(account, Some(BlockNumber::Number(min_diff_block)))
+    // The search stops before `block_end`, so it is checked separately
+    if min_diff_block.is_none() && current_block != block_end {
+        let diff = check_block(web3, retry_policy, block_timestamps, block_end, timestamp).await?;
+        if diff.filter(|diff| *diff >= 0).is_some() {
+            min_diff_block = Some(block_end);
+        }
+    }
+
+    Ok(min_diff_block)
+}
+
+/// Result: balance series of every account, in the same order as `accounts`.
+/// Balances are taken at the end of every period of `interval` within `from..=to`.
+/// Periods which haven't ended yet or ended before the first block are skipped
+pub async fn get_balance_series<T: Transport>(
+    web3: &Web3<T>,
+    retry_policy: &RetryPolicy,
+    block_timestamps: &BlockTimestamps,
+    accounts: &[Address],
+    (from, to): (NaiveDate, NaiveDate),
+    interval: SeriesInterval,
+    max_concurrency: usize,
+) -> Result<Vec<Vec<BalancePoint>>, Box<dyn std::error::Error>> {
+    let block_end = retry_policy
+        .call("eth_blockNumber", || web3.eth().block_number())
+        .await?;
+
+    // (last day of the period, last block of the period)
+    let mut periods = Vec::new();
+    // Period ends are sorted, so every search starts from the block found by the previous one
+    let mut block_start = U64::zero();
+
+    for date in period_ends(from, to, interval) {
+        let period_end = DateTime::<Utc>::from_utc(date.succ().and_hms(0, 0, 0), Utc);
+
+        let block_number = match find_block_in_range(
+            web3,
+            retry_policy,
+            block_timestamps,
+            period_end,
+            block_start,
+            block_end,
+        )
+        .await?
+        {
+            Some(block_number) => block_number,
+            // Period hasn't ended yet, neither have the next ones
+            None => break,
+        };
+        block_start = block_number;
+
+        if !block_number.is_zero() {
+            periods.push((date, block_number - 1));
+        }
+    }
+
+    let requests = periods.iter().flat_map(|(_, block_number)| {
+        accounts
+            .iter()
+            .map(move |account| (*account, *block_number))
+    });
+
+    // Balances of all periods and accounts, period by period
+    let balances: Vec<U256> = stream::iter(requests)
+        .map(|(account, block_number)| async move {
+            retry_policy
+                .call(
+                    &format!(
+                        "eth_getBalance (block {}, account {:?})",
+                        block_number, account
+                    ),
+                    || {
+                        web3.eth()
+                            .balance(account, Some(BlockNumber::Number(block_number)))
+                    },
+                )
+                .await
+        })
+        .buffered(max_concurrency)
+        .try_collect()
+        .await?;
+
+    let series = (0..accounts.len())
+        .map(|i| {
+            periods
+                .iter()
+                .enumerate()
+                .map(|(j, (date, block_number))| BalancePoint {
+                    date: *date,
+                    block_number: *block_number,
+                    balance: balances[j * accounts.len() + i],
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(series)
+}
+
+/// Result: last days of the periods of `interval` within `from..=to`
+fn period_ends(from: NaiveDate, to: NaiveDate, interval: SeriesInterval) -> Vec<NaiveDate> {
+    let mut period_ends = Vec::new();
+
+    let mut date = from;
+    while date <= to {
+        let is_period_end = match interval {
+            SeriesInterval::Day => true,
+            SeriesInterval::Week => date.weekday() == Weekday::Sun,
+            SeriesInterval::Month => date.succ().day() == 1,
+        };
+
+        if is_period_end {
+            period_ends.push(date);
+        }
+
+        date = date.succ();
+    }
+
+    period_ends
+}
+
+/// Result: balances at block `block_number` in the same order as `accounts`
+pub async fn get_balances<T: Transport>(
+    web3: &Web3<T>,
+    retry_policy: &RetryPolicy,
+    accounts: &[Address],
+    block_number: U64,
+) -> Result<Vec<U256>, Box<dyn std::error::Error>> {
+    let mut balances = Vec::with_capacity(accounts.len());
+
+    for account in accounts {
+        let balance = retry_policy
+            .call(
+                &format!(
+                    "eth_getBalance (block {}, account {:?})",
+                    block_number, account
+                ),
+                || {
+                    web3.eth()
+                        .balance(*account, Some(BlockNumber::Number(block_number)))
+                },
+            )
             .await?;
 
-        Some(balance)
-    } else {
-        None
-    };
+        balances.push(balance);
+    }
 
-    Ok(balance)
+    Ok(balances)
+}
+
+/// Result: `balanceOf` of `account` at block `block_number` of every token of `tokens`,
+/// in the same order as `tokens`
+pub async fn get_token_balances<T: Transport>(
+    web3: &Web3<T>,
+    retry_policy: &RetryPolicy,
+    account: Address,
+    tokens: &[Address],
+    block_number: U64,
+) -> Result<Vec<(Address, Option<U256>)>, Box<dyn std::error::Error>> {
+    let mut data = BALANCE_OF_SELECTOR.to_vec();
+    data.extend_from_slice(H256::from(account).as_bytes());
+
+    let mut token_balances = Vec::with_capacity(tokens.len());
+
+    for token in tokens {
+        let request = CallRequest::builder()
+            .to(*token)
+            .data(Bytes(data.clone()))
+            .build();
+
+        let output = retry_policy
+            .call(
+                &format!(
+                    "eth_call (block {}, token {:?}, account {:?})",
+                    block_number, token, account
+                ),
+                || async {
+                    // Reverted call is not retried
+                    match web3
+                        .eth()
+                        .call(
+                            request.clone(),
+                            Some(BlockId::Number(BlockNumber::Number(block_number))),
+                        )
+                        .await
+                    {
+                        Err(e @ web3::Error::Rpc(_)) if !RetryPolicy::is_temporary(&e) => Ok(None),
+                        result => result.map(Some),
+                    }
+                },
+            )
+            .await?;
+
+        // Address without code returns nothing
+        let balance = output
+            .filter(|output| output.0.len() == 32)
+            .map(|output| U256::from_big_endian(&output.0));
+
+        token_balances.push((*token, balance));
+    }
+
+    Ok(token_balances)
 }
 
 /// Move towards earlier blocks
@@ -73,15 +346,26 @@ fn move_right(block_start: &mut U64, current_block: &mut U64, block_end: U64) {
     *current_block = (block_end + *current_block) / 2_u64;
 }
 
-/// Result: `block_timestamp` - `timestamp`
-async fn check_block(
-    web3: &Web3<Http>,
+/// Result: `block_timestamp` - `timestamp`. The block timestamp is saved to `block_timestamps`
+async fn check_block<T: Transport>(
+    web3: &Web3<T>,
+    retry_policy: &RetryPolicy,
+    block_timestamps: &BlockTimestamps,
     block_number: U64,
     timestamp: i64,
 ) -> Result<Option<i64>, Box<dyn std::error::Error>> {
     let block_id = BlockId::Number(BlockNumber::Number(block_number));
 
-    let diff = if let Some(block) = web3.eth().block(block_id).await? {
+    let block = retry_policy
+        .call(
+            &format!("eth_getBlockByNumber (block {})", block_number),
+            || web3.eth().block(block_id),
+        )
+        .await?;
+
+    let diff = if let Some(block) = block {
+        block_timestamps.save(block_number, block.timestamp.as_u64())?;
+
         let block_timestamp = block.timestamp.as_u64() as i64;
         let diff = block_timestamp - timestamp;
 
@@ -92,3 +376,60 @@ async fn check_block(
 
     Ok(diff)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    #[test]
+    fn day_period_ends_are_every_day() {
+        assert_eq!(
+            period_ends(date(2021, 2, 27), date(2021, 3, 2), SeriesInterval::Day),
+            vec![
+                date(2021, 2, 27),
+                date(2021, 2, 28),
+                date(2021, 3, 1),
+                date(2021, 3, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn week_period_ends_are_sundays() {
+        // 2021-03-07 and 2021-03-14 are Sundays
+        assert_eq!(
+            period_ends(date(2021, 3, 1), date(2021, 3, 14), SeriesInterval::Week),
+            vec![date(2021, 3, 7), date(2021, 3, 14)]
+        );
+    }
+
+    #[test]
+    fn range_without_sunday_has_no_week_period_ends() {
+        assert!(period_ends(date(2021, 3, 1), date(2021, 3, 6), SeriesInterval::Week).is_empty());
+    }
+
+    #[test]
+    fn month_period_ends_are_last_days_of_months() {
+        assert_eq!(
+            period_ends(date(2020, 1, 31), date(2020, 4, 29), SeriesInterval::Month),
+            vec![date(2020, 1, 31), date(2020, 2, 29), date(2020, 3, 31)]
+        );
+    }
+
+    #[test]
+    fn month_period_end_crosses_year() {
+        assert_eq!(
+            period_ends(date(2020, 12, 1), date(2021, 1, 31), SeriesInterval::Month),
+            vec![date(2020, 12, 31), date(2021, 1, 31)]
+        );
+    }
+
+    #[test]
+    fn reversed_range_has_no_period_ends() {
+        assert!(period_ends(date(2021, 3, 2), date(2021, 3, 1), SeriesInterval::Day).is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+
+/// Set of block number intervals. Borders are inclusive
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BlockIntervals {
+    /// Sorted, non-overlapping and non-adjacent intervals
+    intervals: Vec<(u64, u64)>,
+}
+
+impl BlockIntervals {
+    /// Adds interval, merging it with overlapping and adjacent ones
+    pub fn insert(&mut self, block_start: u64, block_end: u64) {
+        let mut block_start = block_start;
+        let mut block_end = block_end;
+
+        let mut intervals = Vec::with_capacity(self.intervals.len() + 1);
+
+        for &(start, end) in &self.intervals {
+            if end.saturating_add(1) < block_start || block_end.saturating_add(1) < start {
+                // Not touching the new interval
+                intervals.push((start, end));
+            } else {
+                block_start = block_start.min(start);
+                block_end = block_end.max(end);
+            }
+        }
+
+        intervals.push((block_start, block_end));
+        intervals.sort_unstable();
+
+        self.intervals = intervals;
+    }
+
+    /// Result: parts of `block_start..=block_end` which are inside of the set
+    pub fn covered(&self, block_start: u64, block_end: u64) -> Vec<(u64, u64)> {
+        self.intervals
+            .iter()
+            .filter(|(start, end)| *start <= block_end && *end >= block_start)
+            .map(|(start, end)| ((*start).max(block_start), (*end).min(block_end)))
+            .collect()
+    }
+
+    /// Result: parts of `block_start..=block_end` which are outside of the set
+    pub fn missing(&self, block_start: u64, block_end: u64) -> Vec<(u64, u64)> {
+        let mut missing = Vec::new();
+
+        // `None` means that the set covers everything up to `u64::MAX`
+        let mut next_block = Some(block_start);
+
+        for (start, end) in self.covered(block_start, block_end) {
+            if let Some(next_block) = next_block.filter(|v| *v < start) {
+                missing.push((next_block, start - 1));
+            }
+
+            next_block = end.checked_add(1);
+        }
+
+        if let Some(next_block) = next_block.filter(|v| *v <= block_end) {
+            missing.push((next_block, block_end));
+        }
+
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intervals(ranges: &[(u64, u64)]) -> BlockIntervals {
+        let mut intervals = BlockIntervals::default();
+        for (block_start, block_end) in ranges {
+            intervals.insert(*block_start, *block_end);
+        }
+
+        intervals
+    }
+
+    #[test]
+    fn insert_merges_adjacent_intervals() {
+        let intervals = intervals(&[(10, 19), (20, 29), (0, 9)]);
+
+        assert_eq!(intervals.intervals, vec![(0, 29)]);
+    }
+
+    #[test]
+    fn insert_merges_overlapping_intervals() {
+        let intervals = intervals(&[(10, 20), (30, 40), (15, 35)]);
+
+        assert_eq!(intervals.intervals, vec![(10, 40)]);
+    }
+
+    #[test]
+    fn insert_keeps_separate_intervals_sorted() {
+        let intervals = intervals(&[(30, 40), (10, 20), (22, 25)]);
+
+        assert_eq!(intervals.intervals, vec![(10, 20), (22, 25), (30, 40)]);
+    }
+
+    #[test]
+    fn insert_single_block_intervals() {
+        let intervals = intervals(&[(5, 5), (7, 7), (6, 6), (9, 9)]);
+
+        assert_eq!(intervals.intervals, vec![(5, 7), (9, 9)]);
+    }
+
+    #[test]
+    fn insert_interval_inside_of_another() {
+        let intervals = intervals(&[(10, 40), (20, 30)]);
+
+        assert_eq!(intervals.intervals, vec![(10, 40)]);
+    }
+
+    #[test]
+    fn insert_up_to_max_block() {
+        let intervals = intervals(&[(10, u64::MAX), (0, 9)]);
+
+        assert_eq!(intervals.intervals, vec![(0, u64::MAX)]);
+    }
+
+    #[test]
+    fn covered_is_clipped_to_range() {
+        let intervals = intervals(&[(10, 20), (30, 40)]);
+
+        assert_eq!(intervals.covered(15, 35), vec![(15, 20), (30, 35)]);
+        assert_eq!(intervals.covered(21, 29), vec![]);
+        assert_eq!(intervals.covered(20, 20), vec![(20, 20)]);
+    }
+
+    #[test]
+    fn missing_is_complement_of_covered() {
+        let intervals = intervals(&[(10, 20), (30, 40)]);
+
+        assert_eq!(intervals.missing(0, 50), vec![(0, 9), (21, 29), (41, 50)]);
+        assert_eq!(intervals.missing(10, 40), vec![(21, 29)]);
+        assert_eq!(intervals.missing(12, 18), vec![]);
+    }
+
+    #[test]
+    fn missing_single_blocks() {
+        let intervals = intervals(&[(10, 10), (12, 12)]);
+
+        assert_eq!(intervals.missing(10, 12), vec![(11, 11)]);
+        assert_eq!(intervals.missing(11, 11), vec![(11, 11)]);
+        assert_eq!(intervals.missing(10, 10), vec![]);
+    }
+
+    #[test]
+    fn missing_of_empty_set() {
+        let intervals = BlockIntervals::default();
+
+        assert_eq!(intervals.missing(5, 7), vec![(5, 7)]);
+        assert_eq!(intervals.covered(5, 7), vec![]);
+    }
+
+    #[test]
+    fn missing_up_to_max_block() {
+        let intervals = intervals(&[(10, u64::MAX)]);
+
+        assert_eq!(intervals.missing(0, u64::MAX), vec![(0, 9)]);
+        assert_eq!(intervals.missing(u64::MAX, u64::MAX), vec![]);
+    }
+}
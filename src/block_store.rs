@@ -0,0 +1,284 @@
+use crate::traces::InternalTransaction;
+use std::str;
+use std::str::FromStr;
+use web3::types::{Block, Transaction, TransactionReceipt, H256, U256, U64};
+
+/// Account independent DB of downloaded blocks and receipts.
+/// Receipts are saved as they are fetched for the desired transactions,
+/// so a saved block is processed without server only if receipts of its desired transactions are saved too
+pub struct BlockStore {
+    /// Key - (chain_id, block_number, block_hash)
+    /// Value - block with all its transactions
+    blocks: sled::Tree,
+    /// Key - (chain_id, transaction hash)
+    /// Value - transaction receipt
+    receipts: sled::Tree,
+    /// Key - (chain_id, block_number)
+    /// Value - all internal transactions of the block
+    internal_transactions: sled::Tree,
+    /// Key - (chain_id, block_number)
+    /// Value - block hash.
+    /// Blocks which were above the immutable block when they were scanned.
+    /// They are verified against the server until they become immutable
+    unconfirmed: sled::Tree,
+    chain_id: U256,
+}
+
+impl BlockStore {
+    /// Param `chain_id` - chain of the stored blocks
+    pub fn new(db: &sled::Db, chain_id: U256) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            blocks: db.open_tree("blocks")?,
+            receipts: db.open_tree("receipts")?,
+            internal_transactions: db.open_tree("block_internal_transactions")?,
+            unconfirmed: db.open_tree("unconfirmed_blocks")?,
+            chain_id,
+        })
+    }
+
+    /// Keys of the previous DB format have no chain id.
+    /// Param `keep` - their entries are moved to the chain of the store, otherwise removed
+    pub fn migrate_chainless_keys(&self, keep: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let key_prefix = self.stringify_chain_prefix();
+
+        // Zero padded block numbers start with zero, transaction hashes start with `0x`
+        for tree in [&self.blocks, &self.internal_transactions, &self.unconfirmed] {
+            migrate_chainless_keys(tree, "0", &key_prefix, keep)?;
+        }
+        migrate_chainless_keys(&self.receipts, "0x", &key_prefix, keep)?;
+
+        Ok(())
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    pub fn get_block(
+        &self,
+        block_number: U64,
+    ) -> Result<Option<Block<Transaction>>, Box<dyn std::error::Error>> {
+        let block = self
+            .blocks
+            .scan_prefix(self.stringify_block_key_prefix(block_number))
+            .values()
+            .next()
+            .transpose()?
+            .map(|ivec| serde_json::from_slice(&ivec))
+            .transpose()?;
+
+        Ok(block)
+    }
+
+    /// Result: hash of the saved block `block_number`
+    pub fn get_block_hash(
+        &self,
+        block_number: U64,
+    ) -> Result<Option<H256>, Box<dyn std::error::Error>> {
+        let key_prefix = self.stringify_block_key_prefix(block_number);
+
+        let key = match self.blocks.scan_prefix(&key_prefix).keys().next() {
+            Some(key) => key?,
+            None => return Ok(None),
+        };
+
+        // Key is the prefix followed by the block hash
+        let block_hash = H256::from_str(&str::from_utf8(&key)?[key_prefix.len()..])?;
+
+        Ok(Some(block_hash))
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    pub fn save_block(&self, block: &Block<Transaction>) -> Result<(), Box<dyn std::error::Error>> {
+        let key = self.stringify_block_key(
+            block.number.unwrap_or_default(),
+            block.hash.unwrap_or_default(),
+        );
+
+        // TODO: Remove json layer (serialize directly into bytes)
+        let bytes = serde_json::to_vec(block)?;
+
+        self.blocks.insert(key, bytes)?;
+
+        Ok(())
+    }
+
+    pub fn get_receipt(
+        &self,
+        tr_hash: H256,
+    ) -> Result<Option<TransactionReceipt>, Box<dyn std::error::Error>> {
+        let tr_receipt = self
+            .receipts
+            .get(self.stringify_receipt_key(tr_hash))?
+            .map(|ivec| serde_json::from_slice(&ivec))
+            .transpose()?;
+
+        Ok(tr_receipt)
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    pub fn save_receipt(
+        &self,
+        tr_receipt: &TransactionReceipt,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let key = self.stringify_receipt_key(tr_receipt.transaction_hash);
+
+        // TODO: Remove json layer (serialize directly into bytes)
+        let bytes = serde_json::to_vec(tr_receipt)?;
+
+        self.receipts.insert(key, bytes)?;
+
+        Ok(())
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    pub fn get_internal_transactions(
+        &self,
+        block_number: U64,
+    ) -> Result<Option<Vec<InternalTransaction>>, Box<dyn std::error::Error>> {
+        let internal_transactions = self
+            .internal_transactions
+            .get(self.stringify_block_key_prefix(block_number))?
+            .map(|ivec| serde_json::from_slice(&ivec))
+            .transpose()?;
+
+        Ok(internal_transactions)
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    pub fn save_internal_transactions(
+        &self,
+        block_number: U64,
+        internal_transactions: &[InternalTransaction],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // TODO: Remove json layer (serialize directly into bytes)
+        let bytes = serde_json::to_vec(internal_transactions)?;
+
+        self.internal_transactions
+            .insert(self.stringify_block_key_prefix(block_number), bytes)?;
+
+        Ok(())
+    }
+
+    pub fn save_unconfirmed(
+        &self,
+        block_number: U64,
+        block_hash: H256,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.unconfirmed.insert(
+            self.stringify_block_key_prefix(block_number),
+            block_hash.as_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Result: (block_number, block_hash) of unconfirmed blocks, sorted by block number
+    pub fn get_unconfirmed(&self) -> Result<Vec<(U64, H256)>, Box<dyn std::error::Error>> {
+        let mut blocks = Vec::new();
+
+        let key_prefix = self.stringify_chain_prefix();
+
+        for entry in self.unconfirmed.scan_prefix(&key_prefix) {
+            let (key, value) = entry?;
+
+            let block_number = str::from_utf8(&key)?[key_prefix.len()..]
+                .trim_end_matches('_')
+                .parse::<u64>()?;
+
+            blocks.push((U64::from(block_number), H256::from_slice(&value)));
+        }
+
+        Ok(blocks)
+    }
+
+    /// Forgets unconfirmed blocks up to `block_number` (inclusive), which became immutable
+    pub fn confirm(&self, block_number: U64) -> Result<(), Box<dyn std::error::Error>> {
+        let start = self.stringify_chain_prefix();
+        let end = self.stringify_block_key_prefix(block_number);
+
+        for key in self.unconfirmed.range(start..=end).keys() {
+            self.unconfirmed.remove(key?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes blocks from `block_number` and above, receipts and internal transactions
+    /// of their transactions and their unconfirmed marks, so that they are fetched from server again
+    pub fn remove_from(&self, block_number: U64) -> Result<(), Box<dyn std::error::Error>> {
+        let start = self.stringify_block_key_prefix(block_number);
+        // `tilda` char has biggest code in ASCII table
+        let end = format!("{}~", self.stringify_chain_prefix());
+
+        for entry in self.blocks.range(start.as_str()..end.as_str()) {
+            let (key, value) = entry?;
+
+            // TODO: Remove json layer (serialize directly into bytes)
+            let block: Block<Transaction> = serde_json::from_slice(&value)?;
+
+            for transaction in block.transactions {
+                self.receipts
+                    .remove(self.stringify_receipt_key(transaction.hash))?;
+            }
+
+            self.blocks.remove(key)?;
+        }
+
+        for key in self
+            .internal_transactions
+            .range(start.as_str()..end.as_str())
+            .keys()
+        {
+            self.internal_transactions.remove(key?)?;
+        }
+
+        for key in self.unconfirmed.range(start.as_str()..end.as_str()).keys() {
+            self.unconfirmed.remove(key?)?;
+        }
+
+        Ok(())
+    }
+
+    fn stringify_block_key(&self, block_number: U64, block_hash: H256) -> String {
+        format!(
+            "{}{:?}",
+            self.stringify_block_key_prefix(block_number),
+            block_hash,
+        )
+    }
+
+    fn stringify_block_key_prefix(&self, block_number: U64) -> String {
+        // Add leading zeros to `block_number` string view
+        format!("{}{:0>32?}_", self.stringify_chain_prefix(), block_number)
+    }
+
+    fn stringify_receipt_key(&self, tr_hash: H256) -> String {
+        format!("{}{:?}", self.stringify_chain_prefix(), tr_hash)
+    }
+
+    fn stringify_chain_prefix(&self) -> String {
+        format!("{}_", self.chain_id)
+    }
+}
+
+/// Moves entries of `tree` with keys starting with `old_key_prefix` to keys prefixed
+/// with `key_prefix`, or removes them if not `keep`
+pub fn migrate_chainless_keys(
+    tree: &sled::Tree,
+    old_key_prefix: &str,
+    key_prefix: &str,
+    keep: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in tree.scan_prefix(old_key_prefix) {
+        let (key, value) = entry?;
+
+        if keep {
+            let mut new_key = key_prefix.as_bytes().to_vec();
+            new_key.extend_from_slice(&key);
+
+            tree.insert(new_key, value)?;
+        }
+
+        tree.remove(key)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use web3::types::{U256, U64};
+
+/// DB index of block timestamps, filled with every block header fetched.
+/// Timestamps grow with block numbers, so the known blocks around a timestamp
+/// bound the search of the block of the timestamp
+#[derive(Clone)]
+pub struct BlockTimestamps {
+    /// Key - (chain_id, timestamp, block_number)
+    /// Value - empty
+    block_timestamps: sled::Tree,
+    chain_id: U256,
+}
+
+impl BlockTimestamps {
+    /// Param `chain_id` - chain of the indexed blocks
+    pub fn new(db: &sled::Db, chain_id: U256) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            block_timestamps: db.open_tree("block_timestamps")?,
+            chain_id,
+        })
+    }
+
+    pub fn save(
+        &self,
+        block_number: U64,
+        timestamp: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.block_timestamps
+            .insert(self.stringify_key(timestamp, block_number), vec![])?;
+
+        Ok(())
+    }
+
+    /// Result: (the last known block earlier than `timestamp`,
+    /// the first known block not earlier than `timestamp`), as (block number, block timestamp)
+    #[allow(clippy::type_complexity)]
+    pub fn get_bounds(
+        &self,
+        timestamp: u64,
+    ) -> Result<(Option<(U64, u64)>, Option<(U64, u64)>), Box<dyn std::error::Error>> {
+        let key_prefix = self.stringify_key_prefix();
+        let key_middle = format!("{}{:0>32}_", key_prefix, timestamp);
+
+        let lower = self
+            .block_timestamps
+            .range(key_prefix.clone()..key_middle.clone())
+            .keys()
+            .next_back()
+            .transpose()?
+            .map(|key| Self::parse_key(&key))
+            .transpose()?;
+
+        let upper = self
+            .block_timestamps
+            .range(key_middle..)
+            .keys()
+            .next()
+            .transpose()?
+            .filter(|key| key.starts_with(key_prefix.as_bytes()))
+            .map(|key| Self::parse_key(&key))
+            .transpose()?;
+
+        Ok((lower, upper))
+    }
+
+    /// Removes blocks `block_number` and later, e.g. reorganized ones
+    pub fn remove_from(&self, block_number: U64) -> Result<(), Box<dyn std::error::Error>> {
+        // Later blocks have greater timestamps, so they are at the end
+        for key in self
+            .block_timestamps
+            .scan_prefix(self.stringify_key_prefix())
+            .keys()
+            .rev()
+        {
+            let key = key?;
+            if Self::parse_key(&key)?.0 < block_number {
+                break;
+            }
+
+            self.block_timestamps.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    fn stringify_key(&self, timestamp: u64, block_number: U64) -> String {
+        format!(
+            "{}{:0>32}_{:0>32?}",
+            self.stringify_key_prefix(),
+            timestamp,
+            block_number
+        )
+    }
+
+    fn stringify_key_prefix(&self) -> String {
+        format!("{}_", self.chain_id)
+    }
+
+    /// Result: (block number, timestamp)
+    fn parse_key(key: &[u8]) -> Result<(U64, u64), Box<dyn std::error::Error>> {
+        let key = std::str::from_utf8(key)?;
+
+        let mut parts = key.split('_').skip(1);
+        let timestamp = parts.next().ok_or("Invalid block timestamp key")?.parse()?;
+        let block_number = parts
+            .next()
+            .ok_or("Invalid block timestamp key")?
+            .parse::<u64>()?;
+
+        Ok((U64::from(block_number), timestamp))
+    }
+}
@@ -1,142 +1,554 @@
-use sled::IVec;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use crate::block_intervals::BlockIntervals;
+use crate::block_store::{self, BlockStore};
+use crate::block_timestamps::BlockTimestamps;
+use crate::config::{Config, Confirmations, Traces};
+use crate::progress::Progress;
+use crate::rpc::RetryPolicy;
+use crate::scanned_ranges::ScannedRange;
+use crate::token_metadata::{self, TokenMetadata, TokenMetadataStore};
+use crate::tokens::{self, NftTransfer, TokenTransfer};
+use crate::traces::{self, InternalTransaction};
+use crate::transports::pool::ProviderStats;
+use chrono::Utc;
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use sled::transaction::TransactionResult;
+use sled::Transactional;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
 use std::ops::Range;
 use std::str;
 use std::str::FromStr;
-use web3::transports::Http;
+use tokio::sync::{watch, OnceCell, Semaphore};
 use web3::types::{
-    Address, BlockId, BlockNumber, Transaction, TransactionId, TransactionReceipt, H256, U64,
+    Address, Block, BlockId, BlockNumber, Bytes, CallRequest, FilterBuilder, Log, Transaction,
+    TransactionReceipt, H256, U256, U64,
 };
-use web3::Web3;
+use web3::{BatchTransport, Web3};
 
-pub struct CachedTransactions {
-    web3: Web3<Http>,
+/// (block_timestamp, transaction, transaction_receipt)
+pub type TransactionWithReceipt = (u64, Transaction, Option<TransactionReceipt>);
+
+/// Transactions of one account
+#[derive(Clone, Debug, Default)]
+pub struct AccountTransactions {
+    pub transactions: Vec<TransactionWithReceipt>,
+    /// ETH transfers made by contract calls. Searched only with traces
+    pub internal_transactions: Vec<InternalTransaction>,
+    /// Searched only when token transfers are enabled
+    pub token_transfers: Vec<TokenTransfer>,
+    /// Searched only when NFT transfers are enabled
+    pub nft_transfers: Vec<NftTransfer>,
+}
+
+impl AccountTransactions {
+    pub fn count(&self) -> usize {
+        self.transactions.len()
+            + self.internal_transactions.len()
+            + self.token_transfers.len()
+            + self.nft_transfers.len()
+    }
+
+    /// Moves all transactions of `other` to `self`
+    pub fn append(&mut self, other: &mut Self) {
+        self.transactions.append(&mut other.transactions);
+        self.internal_transactions
+            .append(&mut other.internal_transactions);
+        self.token_transfers.append(&mut other.token_transfers);
+        self.nft_transfers.append(&mut other.nft_transfers);
+    }
+
+    /// Sorts transactions by block number and transaction index,
+    /// internal transactions - also by call path, token and NFT transfers - by block number
+    /// and log index
+    pub fn sort(&mut self) {
+        self.transactions
+            .sort_by_key(|v| (v.1.block_number, v.1.transaction_index));
+        self.internal_transactions.sort_by(|a, b| {
+            (a.block_number, a.transaction_index, &a.call_path).cmp(&(
+                b.block_number,
+                b.transaction_index,
+                &b.call_path,
+            ))
+        });
+        self.token_transfers
+            .sort_by_key(|v| (v.block_number, v.log_index));
+        self.nft_transfers
+            .sort_by_key(|v| (v.block_number, v.log_index, v.batch_index));
+    }
+}
+
+pub struct CachedTransactions<T: BatchTransport + ProviderStats> {
+    web3: Web3<T>,
     db: sled::Db,
+    block_store: BlockStore,
+    token_metadata: TokenMetadataStore,
+    block_timestamps: BlockTimestamps,
+    /// Key - (chain_id, account, block_start, block_end)
+    /// Value - `ScannedRange`
+    scanned_ranges: sled::Tree,
+    /// Key - (account, block_number, transaction_hash, call_path)
+    /// Value - `InternalTransaction`
+    internal_transactions: sled::Tree,
+    /// Key - (account, block_number, log_index)
+    /// Value - `TokenTransfer`
+    token_transfers: sled::Tree,
+    /// Key - (account, block_number, log_index, batch_index)
+    /// Value - `NftTransfer`
+    nft_transfers: sled::Tree,
+    /// Key - (chain_id, account, block_start, block_end)
+    /// Value - `ScannedRange` of token and NFT transfers
+    token_scanned_ranges: sled::Tree,
+    chain_id: U256,
 
     /// HashMap key - account
-    /// HashMap value - (first_block_number, last_block_number)
-    cache_keys: RefCell<HashMap<Address, (U64, U64)>>,
+    /// HashMap value - scanned block intervals
+    cache_keys: RefCell<HashMap<Address, BlockIntervals>>,
+    /// HashMap key - account
+    /// HashMap value - block intervals scanned for token (and NFT, when they are searched) transfers
+    token_cache_keys: RefCell<HashMap<Address, BlockIntervals>>,
+
+    /// Max number of blocks (and batches of one block) processed at the same time.
+    /// Sum of concurrency limits of all providers
+    max_concurrency: usize,
+    /// Max number of requests in one JSON RPC batch
+    batch_size: usize,
+    /// Number of blocks between saves of scan progress
+    checkpoint_interval: u64,
+    /// Becomes `true` when scan should be stopped
+    shutdown: watch::Receiver<bool>,
+    progress: Progress,
+    retry_policy: RetryPolicy,
+    /// Limits the number of in-flight requests to the server
+    requests_limiter: Semaphore,
+    /// Whether server supports `eth_getBlockReceipts`. Detected on first use
+    block_receipts_supported: OnceCell<bool>,
+    confirmations: Confirmations,
+    /// Last block which is considered immutable. Updated by `verify_recent_blocks`
+    immutable_block: Cell<U64>,
+    /// `None` means internal transactions are not searched
+    traces: Option<Traces>,
+    /// `false` means token transfers are not searched
+    find_token_transfers: bool,
+    /// `false` means NFT transfers are not searched
+    find_nft_transfers: bool,
+    /// Max number of blocks in one `eth_getLogs` query
+    logs_range: u64,
 }
 
-impl CachedTransactions {
-    pub async fn new(web3: Web3<Http>) -> Result<Self, Box<dyn std::error::Error>> {
-        let db = sled::open("db")?;
+impl<T: BatchTransport + ProviderStats> CachedTransactions<T> {
+    pub async fn new(
+        web3: Web3<T>,
+        db: sled::Db,
+        config: &Config,
+        retry_policy: RetryPolicy,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.batch_size == 0 {
+            Err("Batch size must be greater than zero")?;
+        }
+        if config.checkpoint_interval == 0 {
+            Err("Checkpoint interval must be greater than zero")?;
+        }
+        if config.logs_range == 0 {
+            Err("Logs range must be greater than zero")?;
+        }
+
+        let token_metadata = TokenMetadataStore::new(&db)?;
+        let scanned_ranges = db.open_tree("scanned_ranges")?;
+        let internal_transactions = db.open_tree("internal_transactions")?;
+        let token_transfers = db.open_tree("token_transfers")?;
+        let nft_transfers = db.open_tree("nft_transfers")?;
+        let token_scanned_ranges = db.open_tree("token_scanned_ranges")?;
 
-        let cache_keys = Self::read_cache_keys(&db).await?;
+        let chain_id = retry_policy
+            .call("eth_chainId", || web3.eth().chain_id())
+            .await?;
+        let block_store = BlockStore::new(&db, chain_id)?;
+        let block_timestamps = BlockTimestamps::new(&db, chain_id)?;
+
+        Self::migrate_chainless_keys(
+            &db,
+            &block_store,
+            &[&internal_transactions, &token_transfers, &nft_transfers],
+            &[&scanned_ranges, &token_scanned_ranges],
+            chain_id,
+        )?;
+
+        let cache_keys = Self::read_scanned_ranges(&scanned_ranges, chain_id, |range| {
+            // Ranges scanned before contract creations were matched miss them
+            range.creations && (config.traces.is_none() || range.traces)
+        })?;
         let cache_keys = RefCell::new(cache_keys);
+        let token_cache_keys =
+            Self::read_scanned_ranges(&token_scanned_ranges, chain_id, |range| {
+                !config.nft_transfers || range.nft_transfers
+            })?;
+        let token_cache_keys = RefCell::new(token_cache_keys);
+
+        let max_concurrency = web3.transport().capacity();
 
         Ok(Self {
             web3,
             db,
+            block_store,
+            token_metadata,
+            block_timestamps,
+            scanned_ranges,
+            internal_transactions,
+            token_transfers,
+            nft_transfers,
+            token_scanned_ranges,
+            chain_id,
             cache_keys,
+            token_cache_keys,
+            max_concurrency,
+            batch_size: config.batch_size,
+            checkpoint_interval: config.checkpoint_interval,
+            shutdown,
+            progress: Progress::new(),
+            retry_policy,
+            requests_limiter: Semaphore::new(max_concurrency),
+            block_receipts_supported: OnceCell::new(),
+            confirmations: config.confirmations.clone(),
+            immutable_block: Cell::new(U64::zero()),
+            traces: config.traces,
+            find_token_transfers: config.token_transfers,
+            find_nft_transfers: config.nft_transfers,
+            logs_range: config.logs_range,
         })
     }
 
-    async fn calculate_borders(
-        cache_keys: Vec<(Address, U64)>,
-    ) -> Result<HashMap<Address, (U64, U64)>, Box<dyn std::error::Error>> {
-        let mut cache_keys_2: HashMap<Address, (U64, U64)> = HashMap::new();
+    /// Keys of transactions and blocks of the previous DB format have no chain id.
+    /// When all scanned ranges are of chain `chain_id`, the entries are moved to it.
+    /// Otherwise their chain is unknown, so they are removed with all scanned ranges
+    /// and scanned again
+    fn migrate_chainless_keys(
+        db: &sled::Db,
+        block_store: &BlockStore,
+        account_trees: &[&sled::Tree],
+        scanned_range_trees: &[&sled::Tree],
+        chain_id: U256,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let db_tree: &sled::Tree = db;
+        let account_trees: Vec<&sled::Tree> =
+            account_trees.iter().cloned().chain([db_tree]).collect();
+
+        let key_prefix = ScannedRange::stringify_key_prefix(chain_id);
+
+        let mut keep = true;
+        for tree in scanned_range_trees {
+            for key in tree.iter().keys() {
+                if !key?.starts_with(key_prefix.as_bytes()) {
+                    keep = false;
+                }
+            }
+        }
 
-        for (account, block_number) in cache_keys {
-            let entry = cache_keys_2
-                .entry(account)
-                .or_insert((block_number, block_number));
+        // Account addresses start with `0x`
+        let is_migrated = account_trees
+            .iter()
+            .all(|tree| tree.scan_prefix("0x").next().is_none());
+        if !is_migrated {
+            if keep {
+                info!("Moving cached transactions to chain {}.", chain_id);
+            } else {
+                warn!(
+                    "Cached transactions of several chains are removed and will be scanned again."
+                );
 
-            // min
-            if block_number < entry.0 {
-                entry.0 = block_number;
+                for tree in scanned_range_trees {
+                    tree.clear()?;
+                }
             }
 
-            // max
-            if block_number > entry.1 {
-                entry.1 = block_number;
+            for tree in account_trees {
+                block_store::migrate_chainless_keys(tree, "0x", &key_prefix, keep)?;
             }
         }
 
-        Ok(cache_keys_2)
+        block_store.migrate_chainless_keys(keep)
     }
 
-    async fn read_cache_keys(
-        db: &sled::Db,
-    ) -> Result<HashMap<Address, (U64, U64)>, Box<dyn std::error::Error>> {
-        let mut errors: Vec<Box<dyn std::error::Error>> = Vec::new();
-        let cache_keys: Vec<IVec> = db
-            .scan_prefix("")
-            .keys()
-            .filter_map(|r| r.map_err(|e| errors.push(Box::new(e))).ok())
-            .collect();
-        let cache_keys: Vec<_> = cache_keys
-            .iter()
-            .map(|v: &IVec| str::from_utf8(v))
-            .filter_map(|r| r.map_err(|e| errors.push(Box::new(e))).ok())
-            .collect();
-        let cache_keys: Vec<_> = cache_keys
-            .iter()
-            .map(|v| Self::parse_key_string_short(v))
-            .filter_map(|r| r.map_err(|e| errors.push(e)).ok())
-            .collect();
+    /// Result: scanned block intervals of every account on chain `chain_id`
+    /// Param `is_usable` - only ranges scanned with all searched data are taken,
+    /// e.g. with traces when internal transactions are searched
+    fn read_scanned_ranges(
+        scanned_ranges: &sled::Tree,
+        chain_id: U256,
+        is_usable: impl Fn(&ScannedRange) -> bool,
+    ) -> Result<HashMap<Address, BlockIntervals>, Box<dyn std::error::Error>> {
+        let mut cache_keys: HashMap<Address, BlockIntervals> = HashMap::new();
 
-        if !errors.is_empty() {
-            Err(errors.swap_remove(0))?;
-        }
+        let key_prefix = ScannedRange::stringify_key_prefix(chain_id);
+
+        for value in scanned_ranges.scan_prefix(key_prefix).values() {
+            let scanned_range: ScannedRange = serde_json::from_slice(&value?)?;
+
+            if !is_usable(&scanned_range) {
+                continue;
+            }
 
-        let cache_keys = Self::calculate_borders(cache_keys).await?;
+            cache_keys.entry(scanned_range.account).or_default().insert(
+                scanned_range.block_start.as_u64(),
+                scanned_range.block_end.as_u64(),
+            );
+        }
 
         Ok(cache_keys)
     }
 
     /// Param `block_end` - `None` means last
-    pub async fn get_by_account(
+    /// Result: transactions of every account, in the same order as `accounts`
+    pub async fn get_by_accounts(
         &self,
-        account: Address,
+        accounts: &[Address],
         block_start: U64,
         block_end: Option<U64>,
-    ) -> Result<Vec<(u64, Transaction, Option<TransactionReceipt>)>, Box<dyn std::error::Error>>
-    {
-        let block_end = block_end.unwrap_or(self.web3.eth().block_number().await?);
+    ) -> Result<Vec<(Address, AccountTransactions)>, Box<dyn std::error::Error>> {
+        let block_end = match block_end {
+            Some(block_end) => block_end,
+            None => {
+                self.limited("eth_blockNumber", || self.web3.eth().block_number())
+                    .await?
+            }
+        };
 
-        let cache_block_numbers = self.cache_keys.borrow().get(&account).cloned();
+        // Reorganized blocks become missing, so they are scanned again
+        self.verify_recent_blocks().await?;
 
-        let need_fetch_from_server =
-            if let Some((old_block_start, old_block_end)) = cache_block_numbers {
-                // If requested borders are inside cached borders - then return `false`
-                // else - return `true`
+        let mut transactions: HashMap<Address, AccountTransactions> = HashMap::new();
+        let mut missing_ranges = Vec::new();
 
-                block_start < old_block_start || block_end > old_block_end
-            } else {
-                true
-            };
+        for &account in accounts {
+            let intervals = self
+                .cache_keys
+                .borrow()
+                .get(&account)
+                .cloned()
+                .unwrap_or_default();
 
-        let transactions = if need_fetch_from_server {
-            self.get_from_server_and_save_to_cache(account, block_start, block_end)
-                .await?
-        } else {
-            self.get_from_cache(account, block_start, block_end).await?
-        };
+            let account_transactions = transactions.entry(account).or_default();
+
+            for (start, end) in intervals.covered(block_start.as_u64(), block_end.as_u64()) {
+                let mut cached_transactions = self
+                    .get_from_cache(account, U64::from(start), U64::from(end))
+                    .await?;
+
+                account_transactions.append(&mut cached_transactions);
+            }
+
+            missing_ranges.push((
+                account,
+                intervals.missing(block_start.as_u64(), block_end.as_u64()),
+            ));
+        }
+
+        // Only not scanned yet parts of the requested range are fetched,
+        // every block - once for all accounts which miss it
+        let segments = Self::split_by_accounts(&missing_ranges);
+
+        self.progress
+            .start(segments.iter().map(|(start, end, _)| end - start + 1).sum());
+
+        let scan_result: Result<(), Box<dyn std::error::Error>> = async {
+            for (start, end, segment_accounts) in segments {
+                let fetched_transactions = self
+                    .get_from_server_and_save_to_cache(
+                        &segment_accounts,
+                        U64::from(start),
+                        U64::from(start),
+                        U64::from(end),
+                    )
+                    .await?;
+
+                for (account, mut account_transactions) in fetched_transactions {
+                    transactions
+                        .entry(account)
+                        .or_default()
+                        .append(&mut account_transactions);
+                }
+            }
+
+            Ok(())
+        }
+        .await;
+
+        self.progress.finish();
+        scan_result?;
+
+        if self.find_token_transfers || self.find_nft_transfers {
+            let token_transfers = self
+                .get_token_transfers(accounts, block_start, block_end, self.find_token_transfers)
+                .await?;
+
+            for (account, mut account_token_transfers) in token_transfers {
+                transactions
+                    .entry(account)
+                    .or_default()
+                    .append(&mut account_token_transfers);
+            }
+        }
+
+        let transactions = accounts
+            .iter()
+            .map(|account| {
+                let mut account_transactions = transactions.remove(account).unwrap_or_default();
+                account_transactions.sort();
+
+                (*account, account_transactions)
+            })
+            .collect();
 
         Ok(transactions)
     }
 
-    /// TODO: Remove json layer (serialize directly into bytes)
+    /// Splits missing block ranges of all accounts into segments
+    /// where every block is missed by the same set of accounts.
+    /// Result: (block_start, block_end, accounts)
+    fn split_by_accounts(
+        missing_ranges: &[(Address, Vec<(u64, u64)>)],
+    ) -> Vec<(u64, u64, Vec<Address>)> {
+        let borders: BTreeSet<u64> = missing_ranges
+            .iter()
+            .flat_map(|(_, ranges)| ranges)
+            .flat_map(|(start, end)| [*start, end.saturating_add(1)])
+            .collect();
+
+        borders
+            .iter()
+            .zip(borders.iter().skip(1))
+            .filter_map(|(&start, &next_start)| {
+                let accounts: Vec<_> = missing_ranges
+                    .iter()
+                    .filter(|(_, ranges)| ranges.iter().any(|(s, e)| *s <= start && start <= *e))
+                    .map(|(account, _)| *account)
+                    .collect();
+
+                if accounts.is_empty() {
+                    None
+                } else {
+                    Some((start, next_start - 1, accounts))
+                }
+            })
+            .collect()
+    }
+
+    /// Processes new blocks as chain head moves, until shutdown.
+    /// Transactions of new blocks are saved to cache and passed to `on_transaction`
+    /// in the order of blocks and transaction indexes.
+    /// Blocks reorganized after they were processed are processed again
+    /// Param `block_start` - first block to process
+    /// Param `heads` - number of the latest chain head
+    pub async fn follow<F>(
+        &self,
+        accounts: &[Address],
+        block_start: U64,
+        mut heads: watch::Receiver<U64>,
+        mut on_transaction: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: FnMut(Address, &TransactionWithReceipt) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        info!("Follow new blocks from block {}.", block_start);
+
+        let mut range_start = block_start;
+        let mut next_block = block_start;
+        let mut shutdown = self.shutdown.clone();
+
+        loop {
+            if *shutdown.borrow() {
+                info!("Follow stopped. Next block: {}.", next_block);
+
+                return Ok(());
+            }
+
+            let head = *heads.borrow_and_update();
+
+            if head >= next_block {
+                if let Some(reorg_block) = self.verify_recent_blocks().await? {
+                    next_block = next_block.min(reorg_block);
+                    range_start = range_start.min(reorg_block);
+                }
+
+                // All blocks of the session extend one scanned range
+                let transactions = self
+                    .get_from_server_and_save_to_cache(accounts, range_start, next_block, head)
+                    .await?;
+
+                let mut transactions: Vec<_> = transactions
+                    .iter()
+                    .flat_map(|(account, account_transactions)| {
+                        account_transactions
+                            .transactions
+                            .iter()
+                            .map(move |transaction| (*account, transaction))
+                    })
+                    .collect();
+                transactions.sort_by_key(|(_, v)| (v.1.block_number, v.1.transaction_index));
+
+                for (account, transaction) in transactions {
+                    on_transaction(account, transaction)?;
+                }
+
+                next_block = head + 1;
+
+                continue;
+            }
+
+            tokio::select! {
+                Ok(()) = shutdown.changed() => {}
+                changed = heads.changed() => {
+                    if changed.is_err() {
+                        Err("New heads watcher stopped")?;
+                    }
+                }
+            }
+        }
+    }
+
     async fn get_from_cache(
         &self,
         account: Address,
         block_start: U64,
         block_end: U64,
-    ) -> Result<Vec<(u64, Transaction, Option<TransactionReceipt>)>, Box<dyn std::error::Error>>
-    {
+    ) -> Result<AccountTransactions, Box<dyn std::error::Error>> {
         info!(
             "From cache. Block start: {:?}. Block end: {:?}",
             block_start, block_end,
         );
 
-        let key_range = Self::stringify_key_range(account, block_start, block_end);
+        let key_range = self.stringify_key_range(account, block_start, block_end);
         debug!("key range: {:?}", key_range);
 
+        let mut transactions = AccountTransactions {
+            transactions: Self::read_key_range(&self.db, key_range.clone())?,
+            internal_transactions: match self.traces {
+                Some(_) => Self::read_key_range(&self.internal_transactions, key_range)?,
+                None => Vec::new(),
+            },
+            // Token transfers are scanned separately
+            token_transfers: Vec::new(),
+            nft_transfers: Vec::new(),
+        };
+
+        // Keys are sorted by transaction hash inside a block - restore transaction index order
+        transactions.sort();
+
+        info!("Got transactions len: {}", transactions.count());
+
+        Ok(transactions)
+    }
+
+    /// Result: values of `tree` in `key_range`
+    fn read_key_range<V: DeserializeOwned>(
+        tree: &sled::Tree,
+        key_range: Range<String>,
+    ) -> Result<Vec<V>, Box<dyn std::error::Error>> {
         let mut errors: Vec<Box<dyn std::error::Error>> = Vec::new();
-        let transactions: Vec<_> = self
-            .db
+        let values: Vec<_> = tree
             .range(key_range)
             .filter_map(|r| r.map_err(|e| errors.push(Box::new(e))).ok())
             .map(|v| {
@@ -144,7 +556,7 @@ impl CachedTransactions {
                 v.1
             })
             .collect();
-        let transactions: Vec<_> = transactions
+        let values: Vec<V> = values
             .into_iter()
             .map(|ivec| {
                 // TODO: Remove json layer (serialize directly into bytes)
@@ -157,120 +569,1734 @@ impl CachedTransactions {
             Err(errors.swap_remove(0))?;
         }
 
-        info!("Got transactions len: {}", transactions.len());
-
-        Ok(transactions)
+        Ok(values)
     }
 
+    /// Param `range_start` - start of the scanned range which `block_start..=block_end` extends.
+    /// Equal to `block_start` for a new range
     async fn get_from_server_and_save_to_cache(
         &self,
-        account: Address,
+        accounts: &[Address],
+        range_start: U64,
         block_start: U64,
         block_end: U64,
-    ) -> Result<Vec<(u64, Transaction, Option<TransactionReceipt>)>, Box<dyn std::error::Error>>
-    {
+    ) -> Result<HashMap<Address, AccountTransactions>, Box<dyn std::error::Error>> {
         info!(
-            "From server. Block start: {:?}. Block end: {:?}",
-            block_start, block_end,
+            "Scan blocks. Block start: {:?}. Block end: {:?}. Accounts len: {}",
+            block_start,
+            block_end,
+            accounts.len(),
         );
 
+        let accounts_set: HashSet<Address> = accounts.iter().cloned().collect();
+
         let block_numbers = block_start.as_u64()..=block_end.as_u64();
 
-        let mut transactions = Vec::new();
+        // Blocks are fetched concurrently, but `buffered` yields them in the original order,
+        // so transactions are saved to DB and returned sorted by block number and transaction index
+        let mut blocks = stream::iter(block_numbers)
+            .map(|block_number| self.get_block_transactions(&accounts_set, U64::from(block_number)))
+            .buffered(self.max_concurrency);
 
-        for block_number in block_numbers {
-            let block_number = U64::from(block_number);
+        let mut transactions: HashMap<Address, AccountTransactions> = accounts
+            .iter()
+            .map(|account| (*account, AccountTransactions::default()))
+            .collect();
+        // Transactions of blocks processed after the last checkpoint
+        let mut new_transactions = transactions.clone();
 
-            let block_id = BlockId::Number(BlockNumber::Number(block_number));
-            let block = self.web3.eth().block(block_id).await?;
+        let mut next_block = block_start;
+        // Last block saved to DB
+        let mut checkpoint_block = (range_start < block_start).then(|| block_start - 1);
 
-            if let Some(block) = block {
-                let block_timestamp = block.timestamp.as_u64();
-                let tr_hashes = block.transactions;
+        let mut shutdown = self.shutdown.clone();
+        let mut interrupted = false;
+        // Error of the first failed block. Blocks before it are saved
+        let mut error = None;
+
+        loop {
+            if *shutdown.borrow() {
+                interrupted = true;
+                break;
+            }
 
-                for tr_hash in tr_hashes {
-                    let tr_id = TransactionId::Hash(tr_hash);
+            let block_transactions = tokio::select! {
+                Ok(()) = shutdown.changed() => continue,
+                block_transactions = blocks.next() => block_transactions,
+            };
+            let (block_transactions, block_internal_transactions) = match block_transactions {
+                Some(Ok(block_transactions)) => block_transactions,
+                Some(Err(e)) => {
+                    error = Some(e);
+                    break;
+                }
+                None => break,
+            };
 
-                    if let Some(transaction) = self.web3.eth().transaction(tr_id).await? {
-                        if transaction.from == Some(account) || transaction.to == Some(account) {
-                            // Desired transaction
+            self.progress
+                .add_block(block_transactions.len() + block_internal_transactions.len());
 
-                            let tr_receipt = self.web3.eth().transaction_receipt(tr_hash).await?;
-                            let key = (account, block_number, tr_hash);
-                            let transaction = (block_timestamp, transaction, tr_receipt);
+            for transaction in block_transactions {
+                // Transaction between two tracked accounts belongs to both of them
+                for account in Self::transaction_accounts(&transaction) {
+                    if let Some(account_transactions) = new_transactions.get_mut(&account) {
+                        account_transactions.transactions.push(transaction.clone());
+                    }
+                }
+            }
 
-                            self.cache_transaction(&key, &transaction).await?;
+            for internal_transaction in block_internal_transactions {
+                let mut tr_accounts = vec![internal_transaction.from, internal_transaction.to];
+                tr_accounts.dedup();
 
-                            transactions.push(transaction);
-                        }
+                for account in tr_accounts {
+                    if let Some(account_transactions) = new_transactions.get_mut(&account) {
+                        account_transactions
+                            .internal_transactions
+                            .push(internal_transaction.clone());
                     }
                 }
             }
+
+            next_block += U64::one();
+
+            let unsaved_blocks = next_block - checkpoint_block.map_or(block_start, |v| v + 1);
+            if unsaved_blocks.as_u64() >= self.checkpoint_interval {
+                self.save_checkpoint(
+                    &mut new_transactions,
+                    &mut transactions,
+                    range_start,
+                    next_block - 1,
+                    &mut checkpoint_block,
+                )
+                .await?;
+            }
+        }
+
+        if next_block > block_start && checkpoint_block != Some(next_block - 1) {
+            self.save_checkpoint(
+                &mut new_transactions,
+                &mut transactions,
+                range_start,
+                next_block - 1,
+                &mut checkpoint_block,
+            )
+            .await?;
         }
 
-        // Save new cached borders
-        self.cache_keys
-            .borrow_mut()
-            .insert(account, (block_start, block_end));
+        let reason = match error {
+            Some(e) => Some(e.to_string()),
+            None if interrupted => Some("Interrupted".to_string()),
+            None => None,
+        };
+
+        if let Some(reason) = reason {
+            let message = match checkpoint_block {
+                Some(checkpoint_block) => format!(
+                    "{}. Blocks up to {} are saved, next run resumes from block {}.",
+                    reason,
+                    checkpoint_block,
+                    checkpoint_block + 1,
+                ),
+                None => format!("{}. Next run resumes from block {}.", reason, block_start),
+            };
+
+            Err(message)?;
+        }
 
-        info!("Got transactions len: {}", transactions.len());
+        info!(
+            "Got transactions len: {}",
+            transactions
+                .values()
+                .map(AccountTransactions::count)
+                .sum::<usize>(),
+        );
 
         Ok(transactions)
     }
 
-    fn stringify_key(account: Address, block_number: U64, tr_hash: H256) -> String {
-        // Add leading zeros to `block_number` string view
-        format!("{:?}_{:0>32?}_{:?}", account, block_number, tr_hash)
+    /// Saves `new_transactions` and extends scanned range `block_start..=checkpoint_block`
+    /// to `block_start..=block_end`. Saved transactions are moved to `transactions`
+    async fn save_checkpoint(
+        &self,
+        new_transactions: &mut HashMap<Address, AccountTransactions>,
+        transactions: &mut HashMap<Address, AccountTransactions>,
+        block_start: U64,
+        block_end: U64,
+        checkpoint_block: &mut Option<U64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.cache_scanned_range(new_transactions, block_start, block_end, *checkpoint_block)?;
+        self.db.flush_async().await?;
+
+        debug!("Checkpoint. Last saved block: {}", block_end);
+
+        let served: Vec<String> = self
+            .web3
+            .transport()
+            .take_served()
+            .into_iter()
+            .map(|(provider, requests)| format!("{} ({} requests)", provider, requests))
+            .collect();
+        if !served.is_empty() {
+            info!(
+                "Blocks {}..={} served by: {}",
+                checkpoint_block.map_or(block_start, |v| v + 1),
+                block_end,
+                served.join(", "),
+            );
+        }
+        *checkpoint_block = Some(block_end);
+
+        for (account, account_transactions) in new_transactions.iter_mut() {
+            transactions
+                .entry(*account)
+                .or_default()
+                .append(account_transactions);
+        }
+
+        Ok(())
     }
 
-    fn stringify_key_range(account: Address, block_start: U64, block_end: U64) -> Range<String> {
-        // Add leading zeros to `block_number` string view
-        let begin = format!("{:?}_{:0>32?}", account, block_start);
+    /// Result: metadata of every token of `tokens`.
+    /// Only tokens without saved metadata are requested from server
+    pub async fn get_token_metadata(
+        &self,
+        tokens: &BTreeSet<Address>,
+    ) -> Result<HashMap<Address, TokenMetadata>, Box<dyn std::error::Error>> {
+        let mut token_metadata = HashMap::new();
+        let mut missing_tokens = Vec::new();
 
-        // Add leading zeros to `block_number` string view
-        // Make max value for `block_number` - `tilda` char has biggest code in ASCII table
-        let end = format!("{:?}_{:0>32?}_~~~", account, block_end);
+        for &token in tokens {
+            match self.token_metadata.get(self.chain_id, token)? {
+                Some(metadata) => {
+                    token_metadata.insert(token, metadata);
+                }
+                None => missing_tokens.push(token),
+            }
+        }
 
-        begin..end
+        if !missing_tokens.is_empty() {
+            info!("Fetch metadata of {} token(s).", missing_tokens.len());
+        }
+
+        let fetched_metadata: Vec<(Address, TokenMetadata)> = stream::iter(missing_tokens)
+            .map(|token| async move {
+                let (symbol, name, decimals) = futures::try_join!(
+                    self.call_token(token, token_metadata::SYMBOL_SELECTOR),
+                    self.call_token(token, token_metadata::NAME_SELECTOR),
+                    self.call_token(token, token_metadata::DECIMALS_SELECTOR),
+                )?;
+
+                let metadata = TokenMetadata {
+                    symbol: symbol.as_ref().and_then(token_metadata::decode_string),
+                    name: name.as_ref().and_then(token_metadata::decode_string),
+                    decimals: decimals.as_ref().and_then(token_metadata::decode_decimals),
+                };
+                self.token_metadata.save(self.chain_id, token, &metadata)?;
+
+                Ok::<_, Box<dyn std::error::Error>>((token, metadata))
+            })
+            .buffer_unordered(self.max_concurrency)
+            .try_collect()
+            .await?;
+
+        token_metadata.extend(fetched_metadata);
+
+        Ok(token_metadata)
+    }
+
+    /// Calls function `selector` without arguments of contract `token` at the latest block.
+    /// Result: `None` - call reverted, e.g. the function isn't implemented
+    async fn call_token(
+        &self,
+        token: Address,
+        selector: [u8; 4],
+    ) -> Result<Option<Bytes>, Box<dyn std::error::Error>> {
+        let request = CallRequest::builder()
+            .to(token)
+            .data(Bytes(selector.to_vec()))
+            .build();
+
+        self.limited(&format!("eth_call (token {:?})", token), || async {
+            match self.web3.eth().call(request.clone(), None).await {
+                Err(e @ web3::Error::Rpc(_)) if !RetryPolicy::is_temporary(&e) => Ok(None),
+                result => result.map(Some),
+            }
+        })
+        .await
+    }
+
+    /// Result: ERC-20 tokens which every account transferred in `0..=block_end`.
+    /// Only not scanned yet parts of the range are fetched from server
+    pub async fn get_transferred_tokens(
+        &self,
+        accounts: &[Address],
+        block_end: U64,
+    ) -> Result<HashMap<Address, BTreeSet<Address>>, Box<dyn std::error::Error>> {
+        let token_transfers = self
+            .get_token_transfers(accounts, U64::zero(), block_end, true)
+            .await?;
+
+        let tokens = token_transfers
+            .into_iter()
+            .map(|(account, account_token_transfers)| {
+                let tokens = account_token_transfers
+                    .token_transfers
+                    .iter()
+                    .map(|v| v.token)
+                    .collect();
+
+                (account, tokens)
+            })
+            .collect();
+
+        Ok(tokens)
     }
 
-    fn parse_key_string(key: &str) -> Result<(Address, U64, H256), Box<dyn std::error::Error>> {
-        let parts: Vec<&str> = key.split('_').collect();
-        assert_eq!(parts.len(), 3);
+    /// Result: token and NFT transfers of every account in `block_start..=block_end`,
+    /// only of the enabled kinds. Only not scanned yet parts of the range are fetched from server
+    /// Param `with_token_transfers` - token transfers are taken even if they aren't searched
+    async fn get_token_transfers(
+        &self,
+        accounts: &[Address],
+        block_start: U64,
+        block_end: U64,
+        with_token_transfers: bool,
+    ) -> Result<HashMap<Address, AccountTransactions>, Box<dyn std::error::Error>> {
+        let mut token_transfers: HashMap<Address, AccountTransactions> = HashMap::new();
+        let mut missing_ranges = Vec::new();
+
+        for &account in accounts {
+            let intervals = self
+                .token_cache_keys
+                .borrow()
+                .get(&account)
+                .cloned()
+                .unwrap_or_default();
+
+            let account_token_transfers = token_transfers.entry(account).or_default();
+
+            for (start, end) in intervals.covered(block_start.as_u64(), block_end.as_u64()) {
+                let key_range = self.stringify_key_range(account, U64::from(start), U64::from(end));
+
+                if with_token_transfers {
+                    account_token_transfers
+                        .token_transfers
+                        .extend(Self::read_key_range(
+                            &self.token_transfers,
+                            key_range.clone(),
+                        )?);
+                }
+                if self.find_nft_transfers {
+                    account_token_transfers
+                        .nft_transfers
+                        .extend(Self::read_key_range(&self.nft_transfers, key_range)?);
+                }
+            }
+
+            missing_ranges.push((
+                account,
+                intervals.missing(block_start.as_u64(), block_end.as_u64()),
+            ));
+        }
 
-        let account = parts[0].parse()?;
-        let block_number = U64::from(parts[1].parse::<u64>()?);
-        let tr_hash = H256::from_str(parts[2])?;
+        for (start, end, segment_accounts) in Self::split_by_accounts(&missing_ranges) {
+            let fetched_token_transfers = self
+                .get_token_transfers_from_server_and_save_to_cache(
+                    &segment_accounts,
+                    U64::from(start),
+                    U64::from(end),
+                )
+                .await?;
+
+            for (account, mut account_token_transfers) in fetched_token_transfers {
+                // Token transfers are always found by the same queries, but shown only when enabled
+                if !with_token_transfers {
+                    account_token_transfers.token_transfers.clear();
+                }
+
+                token_transfers
+                    .entry(account)
+                    .or_default()
+                    .append(&mut account_token_transfers);
+            }
+        }
 
-        let res = (account, block_number, tr_hash);
-        Ok(res)
+        Ok(token_transfers)
     }
 
-    fn parse_key_string_short(key: &str) -> Result<(Address, U64), Box<dyn std::error::Error>> {
-        let parts: Vec<&str> = key.split('_').collect();
-        assert!(parts.len() >= 2);
+    /// Fetches token transfers, and NFT transfers when they are searched,
+    /// with `eth_getLogs` queries of up to `logs_range` blocks.
+    /// Windows of `logs_range` blocks are queried concurrently, up to `max_concurrency` at a time.
+    /// Transfers are saved and the scanned range is extended after every window, in the block order
+    async fn get_token_transfers_from_server_and_save_to_cache(
+        &self,
+        accounts: &[Address],
+        block_start: U64,
+        block_end: U64,
+    ) -> Result<HashMap<Address, AccountTransactions>, Box<dyn std::error::Error>> {
+        info!(
+            "Scan token transfers. Block start: {:?}. Block end: {:?}. Accounts len: {}",
+            block_start,
+            block_end,
+            accounts.len(),
+        );
+
+        let mut token_transfers: HashMap<Address, AccountTransactions> = accounts
+            .iter()
+            .map(|account| (*account, AccountTransactions::default()))
+            .collect();
+
+        let mut windows = Vec::new();
+        let mut window_start = block_start;
+        while window_start <= block_end {
+            let window_end = (window_start + self.logs_range - 1).min(block_end);
+            windows.push((window_start, window_end));
+
+            window_start = window_end + 1;
+        }
+
+        // Windows are fetched concurrently, but `buffered` yields them in the original order,
+        // so the scanned range is extended without gaps
+        let mut windows = stream::iter(windows)
+            .map(|(window_start, window_end)| async move {
+                let logs = self
+                    .get_window_transfer_logs(accounts, window_start, window_end)
+                    .await?;
+                let transfers = self.transfers_from_logs(&logs).await?;
+
+                Ok::<_, Box<dyn std::error::Error>>((window_start, window_end, transfers))
+            })
+            .buffered(self.max_concurrency);
+
+        let mut next_block = block_start;
+        let mut shutdown = self.shutdown.clone();
 
-        let account = parts[0].parse()?;
-        let block_number = U64::from(parts[1].parse::<u64>()?);
+        loop {
+            if *shutdown.borrow() {
+                let message = if next_block > block_start {
+                    format!(
+                        "Interrupted. Token transfers up to block {} are saved, next run resumes from block {}.",
+                        next_block - 1,
+                        next_block,
+                    )
+                } else {
+                    format!("Interrupted. Next run resumes from block {}.", block_start)
+                };
 
-        let res = (account, block_number);
-        Ok(res)
+                Err(message)?;
+            }
+
+            let window = tokio::select! {
+                Ok(()) = shutdown.changed() => continue,
+                window = windows.next() => window,
+            };
+            let (window_start, window_end, (logs_token_transfers, logs_nft_transfers)) =
+                match window {
+                    Some(window) => window?,
+                    None => break,
+                };
+
+            let mut new_token_transfers: HashMap<Address, AccountTransactions> = accounts
+                .iter()
+                .map(|account| (*account, AccountTransactions::default()))
+                .collect();
+
+            // Transfer between two tracked accounts belongs to both of them
+            for token_transfer in logs_token_transfers {
+                let mut tr_accounts = vec![token_transfer.from, token_transfer.to];
+                tr_accounts.dedup();
+
+                for account in tr_accounts {
+                    if let Some(account_token_transfers) = new_token_transfers.get_mut(&account) {
+                        account_token_transfers
+                            .token_transfers
+                            .push(token_transfer.clone());
+                    }
+                }
+            }
+
+            for nft_transfer in logs_nft_transfers {
+                let mut tr_accounts = vec![nft_transfer.from, nft_transfer.to];
+                tr_accounts.dedup();
+
+                for account in tr_accounts {
+                    if let Some(account_token_transfers) = new_token_transfers.get_mut(&account) {
+                        account_token_transfers
+                            .nft_transfers
+                            .push(nft_transfer.clone());
+                    }
+                }
+            }
+
+            self.cache_token_transfers(
+                &new_token_transfers,
+                block_start,
+                window_end,
+                (window_start > block_start).then(|| window_start - 1),
+            )?;
+
+            for (account, mut account_token_transfers) in new_token_transfers {
+                token_transfers
+                    .entry(account)
+                    .or_default()
+                    .append(&mut account_token_transfers);
+            }
+
+            next_block = window_end + 1;
+        }
+
+        self.db.flush_async().await?;
+
+        info!(
+            "Got token transfers len: {}",
+            token_transfers
+                .values()
+                .map(AccountTransactions::count)
+                .sum::<usize>(),
+        );
+
+        Ok(token_transfers)
     }
 
-    /// TODO: Remove json layer (serialize directly into bytes)
-    /// Save transaction to DB
-    async fn cache_transaction(
+    /// Result: transfer logs of `block_start..=block_end` (see `get_transfer_logs`).
+    /// Query range is halved while server refuses it because of too many logs,
+    /// and grows back after successful queries
+    async fn get_window_transfer_logs(
         &self,
-        key: &(Address, U64, H256),
-        transaction: &(u64, Transaction, Option<TransactionReceipt>),
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let key = Self::stringify_key(key.0, key.1, key.2);
-        debug!("key: {}", key);
+        accounts: &[Address],
+        block_start: U64,
+        block_end: U64,
+    ) -> Result<Vec<Log>, Box<dyn std::error::Error>> {
+        let mut logs = Vec::new();
 
-        // TODO: Remove json layer (serialize directly into bytes)
-        let bytes = serde_json::to_vec(&transaction)?;
+        let mut next_block = block_start;
+        let mut logs_range = self.logs_range;
 
-        self.db.insert(key, bytes)?;
+        while next_block <= block_end {
+            let range_end = (next_block + logs_range - 1).min(block_end);
+
+            match self
+                .get_transfer_logs(accounts, next_block, range_end)
+                .await?
+            {
+                Ok(mut range_logs) => logs.append(&mut range_logs),
+                Err(e) if range_end > next_block => {
+                    debug!(
+                        "Too many logs in blocks {}..={}: {}. Splitting the range.",
+                        next_block, range_end, e,
+                    );
+
+                    logs_range = (range_end - next_block).as_u64().div_ceil(2);
+                    continue;
+                }
+                Err(e) => Err(e)?,
+            }
+
+            next_block = range_end + 1;
+            logs_range = (logs_range * 2).min(self.logs_range);
+        }
+
+        Ok(logs)
+    }
+
+    /// Result: `Transfer` logs, and ERC-1155 transfer logs when NFT transfers are searched,
+    /// of `block_start..=block_end` from or to any of `accounts`, sorted by block number and log index.
+    /// Inner error - server refused the query because of too many logs
+    async fn get_transfer_logs(
+        &self,
+        accounts: &[Address],
+        block_start: U64,
+        block_end: U64,
+    ) -> Result<web3::Result<Vec<Log>>, Box<dyn std::error::Error>> {
+        let transfer_topic = H256::from_str(tokens::TRANSFER_TOPIC)?;
+        let account_topics: Vec<H256> = accounts
+            .iter()
+            .map(|account| tokens::address_to_topic(*account))
+            .collect();
+
+        let filter = FilterBuilder::default()
+            .from_block(BlockNumber::Number(block_start))
+            .to_block(BlockNumber::Number(block_end));
+
+        // Account is either the sender (topic 1) or the receiver (topic 2)
+        let mut filters = vec![
+            filter
+                .clone()
+                .topics(
+                    Some(vec![transfer_topic]),
+                    Some(account_topics.clone()),
+                    None,
+                    None,
+                )
+                .build(),
+            filter
+                .clone()
+                .topics(
+                    Some(vec![transfer_topic]),
+                    None,
+                    Some(account_topics.clone()),
+                    None,
+                )
+                .build(),
+        ];
+
+        // ERC-1155 events have the operator in topic 1, the sender in topic 2
+        // and the receiver in topic 3
+        if self.find_nft_transfers {
+            let erc1155_topics = vec![
+                H256::from_str(tokens::TRANSFER_SINGLE_TOPIC)?,
+                H256::from_str(tokens::TRANSFER_BATCH_TOPIC)?,
+            ];
+
+            filters.push(
+                filter
+                    .clone()
+                    .topics(
+                        Some(erc1155_topics.clone()),
+                        None,
+                        Some(account_topics.clone()),
+                        None,
+                    )
+                    .build(),
+            );
+            filters.push(
+                filter
+                    .topics(Some(erc1155_topics), None, None, Some(account_topics))
+                    .build(),
+            );
+        }
+
+        let mut logs = Vec::new();
+
+        for filter in filters {
+            let filter_logs = self
+                .limited(
+                    &format!("eth_getLogs (blocks {}..={})", block_start, block_end),
+                    || async {
+                        // Too many logs is not retried, the range is split instead
+                        match self.web3.eth().logs(filter.clone()).await {
+                            Err(e) if RetryPolicy::is_too_many_logs(&e) => Ok(Err(e)),
+                            result => result.map(Ok),
+                        }
+                    },
+                )
+                .await?;
+
+            match filter_logs {
+                Ok(filter_logs) => logs.extend(filter_logs),
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+
+        // Transfer between two tracked accounts is found by both queries
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+        logs.dedup_by_key(|log| (log.block_number, log.log_index));
+
+        Ok(Ok(logs))
+    }
+
+    /// Result: (token transfers, NFT transfers) of `logs`, with timestamps of their blocks.
+    /// NFT transfers are empty when they are not searched
+    async fn transfers_from_logs(
+        &self,
+        logs: &[Log],
+    ) -> Result<(Vec<TokenTransfer>, Vec<NftTransfer>), Box<dyn std::error::Error>> {
+        let block_numbers: BTreeSet<U64> = logs.iter().filter_map(|log| log.block_number).collect();
+        let timestamps = self.get_block_timestamps(block_numbers).await?;
+
+        let mut token_transfers = Vec::new();
+        let mut nft_transfers = Vec::new();
+
+        for log in logs {
+            let timestamp = match log.block_number.and_then(|v| timestamps.get(&v)) {
+                Some(timestamp) => *timestamp,
+                None => continue,
+            };
+
+            if let Some(token_transfer) = tokens::from_transfer_log(log, timestamp) {
+                token_transfers.push(token_transfer);
+            } else if self.find_nft_transfers {
+                nft_transfers.extend(tokens::from_nft_log(log, timestamp));
+            }
+        }
+
+        Ok((token_transfers, nft_transfers))
+    }
+
+    /// Result: key - block number, value - block timestamp
+    async fn get_block_timestamps(
+        &self,
+        block_numbers: BTreeSet<U64>,
+    ) -> Result<HashMap<U64, u64>, Box<dyn std::error::Error>> {
+        let first_block = match block_numbers.iter().next() {
+            Some(block_number) => *block_number,
+            None => return Ok(HashMap::new()),
+        };
+
+        let params = block_numbers
+            .iter()
+            .map(|block_number| {
+                Ok(vec![
+                    serde_json::to_value(block_number)?,
+                    Value::Bool(false),
+                ])
+            })
+            .collect::<Result<_, serde_json::Error>>()?;
+        // Logs may come from a provider at the head, and headers - from a lagging one
+        let headers: Vec<Block<H256>> = self
+            .batch_call(
+                "eth_getBlockByNumber",
+                first_block,
+                params,
+                Some(Self::block_not_found),
+            )
+            .await?;
+
+        let mut timestamps = HashMap::new();
+        for (block_number, header) in block_numbers.into_iter().zip(headers) {
+            self.block_timestamps
+                .save(block_number, header.timestamp.as_u64())?;
+
+            timestamps.insert(block_number, header.timestamp.as_u64());
+        }
+
+        Ok(timestamps)
+    }
+
+    /// Result: (desired transactions, desired internal transactions) of block `block_number`,
+    /// sorted by transaction index
+    async fn get_block_transactions(
+        &self,
+        accounts: &HashSet<Address>,
+        block_number: U64,
+    ) -> Result<(Vec<TransactionWithReceipt>, Vec<InternalTransaction>), Box<dyn std::error::Error>>
+    {
+        let block = self.get_block(block_number).await?;
+        let block_timestamp = block.timestamp.as_u64();
+
+        // Blocks above the immutable block may be reorganized, so they are verified on every run
+        if block_number > self.immutable_block.get() {
+            self.block_store
+                .save_unconfirmed(block_number, block.hash.unwrap_or_default())?;
+        }
+
+        let internal_transactions = match self.traces {
+            Some(traces) => self
+                .get_internal_transactions(&block, traces)
+                .await?
+                .into_iter()
+                .filter(|v| accounts.contains(&v.from) || accounts.contains(&v.to))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // Contract creations have no `to`, the created contract is known from the receipt only
+        let transactions: Vec<_> = block
+            .transactions
+            .into_iter()
+            .filter(|transaction| {
+                transaction.to.is_none()
+                    || [transaction.from, transaction.to]
+                        .iter()
+                        .flatten()
+                        .any(|account| accounts.contains(account))
+            })
+            .collect();
+
+        let tr_hashes = transactions
+            .iter()
+            .map(|transaction| transaction.hash)
+            .collect();
+        let tr_receipts = self.get_receipts(block_number, tr_hashes).await?;
+
+        let transactions = transactions
+            .into_iter()
+            .zip(tr_receipts)
+            .map(|(transaction, tr_receipt)| (block_timestamp, transaction, tr_receipt))
+            .filter(|transaction| {
+                Self::transaction_accounts(transaction)
+                    .iter()
+                    .any(|account| accounts.contains(account))
+            })
+            .collect();
+
+        Ok((transactions, internal_transactions))
+    }
+
+    /// Result: all internal transactions of `block`, from DB or from server
+    async fn get_internal_transactions(
+        &self,
+        block: &Block<Transaction>,
+        traces: Traces,
+    ) -> Result<Vec<InternalTransaction>, Box<dyn std::error::Error>> {
+        let block_number = block.number.unwrap_or_default();
+
+        if let Some(internal_transactions) =
+            self.block_store.get_internal_transactions(block_number)?
+        {
+            return Ok(internal_transactions);
+        }
+
+        let internal_transactions = match traces {
+            Traces::Parity => {
+                let params = vec![serde_json::to_value(block_number)?];
+                let block_traces = self
+                    .limited(&format!("trace_block (block {})", block_number), || {
+                        self.web3.transport().execute("trace_block", params.clone())
+                    })
+                    .await?;
+
+                traces::from_parity_traces(block, block_traces)?
+            }
+            Traces::Geth => {
+                let params = vec![
+                    serde_json::to_value(block_number)?,
+                    json!({ "tracer": "callTracer" }),
+                ];
+                let block_traces = self
+                    .limited(
+                        &format!("debug_traceBlockByNumber (block {})", block_number),
+                        || {
+                            self.web3
+                                .transport()
+                                .execute("debug_traceBlockByNumber", params.clone())
+                        },
+                    )
+                    .await?;
+
+                traces::from_geth_traces(block, block_traces)?
+            }
+        };
+
+        self.block_store
+            .save_internal_transactions(block_number, &internal_transactions)?;
+
+        Ok(internal_transactions)
+    }
+
+    /// Result: sender, receiver and created contract of the transaction, without duplicates
+    fn transaction_accounts(transaction: &TransactionWithReceipt) -> Vec<Address> {
+        let contract_address = transaction
+            .2
+            .as_ref()
+            .and_then(|tr_receipt| tr_receipt.contract_address);
+
+        let mut tr_accounts: Vec<Address> =
+            [transaction.1.from, transaction.1.to, contract_address]
+                .into_iter()
+                .flatten()
+                .collect();
+        tr_accounts.sort_unstable();
+        tr_accounts.dedup();
+
+        tr_accounts
+    }
+
+    /// Verifies hashes of unconfirmed blocks and their links to parent blocks against the server.
+    /// Cached data from the first reorganized block is rolled back.
+    /// Unconfirmed blocks which became immutable are verified for the last time
+    /// Result: first reorganized block
+    async fn verify_recent_blocks(&self) -> Result<Option<U64>, Box<dyn std::error::Error>> {
+        let immutable_block = self.get_immutable_block().await?;
+        self.immutable_block.set(immutable_block);
+
+        let unconfirmed_blocks = self.block_store.get_unconfirmed()?;
+        let first_block = match unconfirmed_blocks.first() {
+            Some((block_number, _)) => *block_number,
+            None => return Ok(None),
+        };
+
+        let params = unconfirmed_blocks
+            .iter()
+            .map(|(block_number, _)| {
+                Ok(vec![
+                    serde_json::to_value(block_number)?,
+                    Value::Bool(false),
+                ])
+            })
+            .collect::<Result<_, serde_json::Error>>()?;
+        // Block which server doesn't have yet is retried, e.g. by a provider closer to the head
+        let headers: Vec<Block<H256>> = self
+            .batch_call(
+                "eth_getBlockByNumber",
+                first_block,
+                params,
+                Some(Self::block_not_found),
+            )
+            .await?;
+
+        let mut reorg_block = None;
+
+        for ((block_number, block_hash), header) in unconfirmed_blocks.iter().zip(headers) {
+            // Block is replaced
+            if header.hash != Some(*block_hash) {
+                reorg_block = Some(*block_number);
+                break;
+            }
+
+            if block_number.is_zero() {
+                continue;
+            }
+
+            let parent_number = *block_number - 1;
+            let parent_hash = self.block_store.get_block_hash(parent_number)?;
+
+            if parent_hash.is_some_and(|v| v != header.parent_hash) {
+                reorg_block = Some(parent_number);
+                break;
+            }
+        }
+
+        match reorg_block {
+            Some(reorg_block) => {
+                warn!(
+                    "Chain reorganization at block {}. Cached data from this block is rolled back and scanned again.",
+                    reorg_block,
+                );
+
+                self.roll_back(reorg_block).await?;
+            }
+            None => self.block_store.confirm(immutable_block)?,
+        }
+
+        Ok(reorg_block)
+    }
+
+    /// Result: last block which is considered immutable by `confirmations`
+    async fn get_immutable_block(&self) -> Result<U64, Box<dyn std::error::Error>> {
+        let immutable_block = match &self.confirmations {
+            Confirmations::Depth(depth) => {
+                let head = self
+                    .limited("eth_blockNumber", || self.web3.eth().block_number())
+                    .await?;
+
+                head.saturating_sub(U64::from(*depth))
+            }
+            Confirmations::Tag(tag) => {
+                let params = vec![Value::from(tag.as_str()), Value::Bool(false)];
+                let block = self
+                    .limited(&format!("eth_getBlockByNumber (block {})", tag), || {
+                        self.web3
+                            .transport()
+                            .execute("eth_getBlockByNumber", params.clone())
+                    })
+                    .await?;
+                let block: Option<Block<H256>> = serde_json::from_value(block)?;
+
+                block
+                    .and_then(|block| block.number)
+                    .ok_or_else(|| format!("Server has no {} block", tag))?
+            }
+        };
+
+        Ok(immutable_block)
+    }
+
+    /// Removes cached data of blocks from `block_number` and above:
+    /// account transactions, token and NFT transfers, scanned ranges, blocks and receipts
+    async fn roll_back(&self, block_number: U64) -> Result<(), Box<dyn std::error::Error>> {
+        let (removed_scanned_range_keys, cut_scanned_range_entries, mut accounts) =
+            self.cut_scanned_ranges(&self.scanned_ranges, block_number)?;
+        let (removed_token_range_keys, cut_token_range_entries, token_accounts) =
+            self.cut_scanned_ranges(&self.token_scanned_ranges, block_number)?;
+        accounts.extend(token_accounts);
+
+        let mut transaction_keys = Vec::new();
+        let mut internal_transaction_keys = Vec::new();
+        let mut token_transfer_keys = Vec::new();
+        let mut nft_transfer_keys = Vec::new();
+        for account in accounts {
+            let key_range = self.stringify_key_range(account, block_number, U64::MAX);
+
+            for key in self.db.range(key_range.clone()).keys() {
+                transaction_keys.push(key?);
+            }
+            for key in self.internal_transactions.range(key_range.clone()).keys() {
+                internal_transaction_keys.push(key?);
+            }
+            for key in self.token_transfers.range(key_range.clone()).keys() {
+                token_transfer_keys.push(key?);
+            }
+            for key in self.nft_transfers.range(key_range).keys() {
+                nft_transfer_keys.push(key?);
+            }
+        }
+
+        let result: TransactionResult<(), sled::Error> = (
+            &*self.db,
+            &self.internal_transactions,
+            &self.scanned_ranges,
+            &self.token_transfers,
+            &self.nft_transfers,
+            &self.token_scanned_ranges,
+        )
+            .transaction(
+                |(
+                    db,
+                    internal_transactions,
+                    scanned_ranges,
+                    token_transfers,
+                    nft_transfers,
+                    token_scanned_ranges,
+                )| {
+                    for key in &transaction_keys {
+                        db.remove(key.clone())?;
+                    }
+
+                    for key in &internal_transaction_keys {
+                        internal_transactions.remove(key.clone())?;
+                    }
+
+                    for key in &token_transfer_keys {
+                        token_transfers.remove(key.clone())?;
+                    }
+
+                    for key in &nft_transfer_keys {
+                        nft_transfers.remove(key.clone())?;
+                    }
+
+                    for key in &removed_scanned_range_keys {
+                        scanned_ranges.remove(key.as_str())?;
+                    }
+
+                    for (key, bytes) in &cut_scanned_range_entries {
+                        scanned_ranges.insert(key.as_str(), bytes.as_slice())?;
+                    }
+
+                    for key in &removed_token_range_keys {
+                        token_scanned_ranges.remove(key.as_str())?;
+                    }
+
+                    for (key, bytes) in &cut_token_range_entries {
+                        token_scanned_ranges.insert(key.as_str(), bytes.as_slice())?;
+                    }
+
+                    Ok(())
+                },
+            );
+        result?;
+
+        // Blocks are removed after the ranges, so that interrupted rollback is detected again
+        self.block_store.remove_from(block_number)?;
+        self.block_timestamps.remove_from(block_number)?;
+        self.db.flush_async().await?;
+
+        *self.cache_keys.borrow_mut() =
+            Self::read_scanned_ranges(&self.scanned_ranges, self.chain_id, |range| {
+                range.creations && (self.traces.is_none() || range.traces)
+            })?;
+        *self.token_cache_keys.borrow_mut() =
+            Self::read_scanned_ranges(&self.token_scanned_ranges, self.chain_id, |range| {
+                !self.find_nft_transfers || range.nft_transfers
+            })?;
+
+        Ok(())
+    }
+
+    /// Ranges which end before `block_number` stay as they are, others are cut or removed
+    /// Result: (keys of ranges to remove, entries of cut ranges to insert, accounts of all ranges)
+    #[allow(clippy::type_complexity)]
+    fn cut_scanned_ranges(
+        &self,
+        scanned_ranges: &sled::Tree,
+        block_number: U64,
+    ) -> Result<(Vec<String>, Vec<(String, Vec<u8>)>, HashSet<Address>), Box<dyn std::error::Error>>
+    {
+        let mut removed_keys = Vec::new();
+        let mut cut_entries = Vec::new();
+        let mut accounts = HashSet::new();
+
+        let key_prefix = ScannedRange::stringify_key_prefix(self.chain_id);
+        for value in scanned_ranges.scan_prefix(key_prefix).values() {
+            let mut scanned_range: ScannedRange = serde_json::from_slice(&value?)?;
+
+            accounts.insert(scanned_range.account);
+
+            if scanned_range.block_end < block_number {
+                continue;
+            }
+
+            removed_keys.push(scanned_range.key());
+
+            if scanned_range.block_start < block_number {
+                scanned_range.block_end = block_number - 1;
+
+                cut_entries.push((scanned_range.key(), serde_json::to_vec(&scanned_range)?));
+            }
+        }
+
+        Ok((removed_keys, cut_entries, accounts))
+    }
+
+    /// Result: all receipts of block `block_number` by their transaction hashes,
+    /// fetched with `eth_getBlockReceipts` and saved to DB.
+    /// `None` - not enough providers support `eth_getBlockReceipts`
+    async fn get_block_receipts(
+        &self,
+        block_number: U64,
+    ) -> Result<Option<HashMap<H256, TransactionReceipt>>, Box<dyn std::error::Error>> {
+        if !self.is_block_receipts_supported().await? {
+            return Ok(None);
+        }
+
+        // All receipts of the block in one call
+        let params = vec![serde_json::to_value(block_number)?];
+        let block_receipts = self
+            .limited(
+                &format!("eth_getBlockReceipts (block {})", block_number),
+                || async {
+                    // With quorum the method may be supported by too few providers
+                    match self
+                        .web3
+                        .transport()
+                        .execute("eth_getBlockReceipts", params.clone())
+                        .await
+                    {
+                        Err(e) if RetryPolicy::is_method_not_found(&e) => Ok(None),
+                        result => result.map(Some),
+                    }
+                },
+            )
+            .await?;
+        let block_receipts = match block_receipts {
+            Some(block_receipts) => block_receipts,
+            None => return Ok(None),
+        };
+        let block_receipts: Option<Vec<TransactionReceipt>> =
+            serde_json::from_value(block_receipts)?;
+
+        let block_receipts: HashMap<H256, TransactionReceipt> = block_receipts
+            .unwrap_or_default()
+            .into_iter()
+            .map(|tr_receipt| (tr_receipt.transaction_hash, tr_receipt))
+            .collect();
+
+        for tr_receipt in block_receipts.values() {
+            self.block_store.save_receipt(tr_receipt)?;
+        }
+
+        Ok(Some(block_receipts))
+    }
+
+    /// Result: receipts in the same order as `tr_hashes`
+    async fn get_receipts(
+        &self,
+        block_number: U64,
+        tr_hashes: Vec<H256>,
+    ) -> Result<Vec<Option<TransactionReceipt>>, Box<dyn std::error::Error>> {
+        let cached_tr_receipts = tr_hashes
+            .iter()
+            .map(|tr_hash| self.block_store.get_receipt(*tr_hash))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if cached_tr_receipts.iter().all(Option::is_some) {
+            return Ok(cached_tr_receipts);
+        }
+
+        let tr_receipts =
+            if let Some(mut block_receipts) = self.get_block_receipts(block_number).await? {
+                tr_hashes
+                    .iter()
+                    .map(|tr_hash| block_receipts.remove(tr_hash))
+                    .collect()
+            } else {
+                let params = tr_hashes
+                    .iter()
+                    .map(|tr_hash| Ok(vec![serde_json::to_value(tr_hash)?]))
+                    .collect::<Result<_, serde_json::Error>>()?;
+                let tr_receipts: Vec<Option<TransactionReceipt>> = self
+                    .batch_call("eth_getTransactionReceipt", block_number, params, None)
+                    .await?;
+
+                for (tr_hash, tr_receipt) in tr_hashes.iter().zip(&tr_receipts) {
+                    if let Some(tr_receipt) = tr_receipt {
+                        if tr_receipt.transaction_hash != *tr_hash {
+                            Err(format!(
+                                "Receipt of transaction {:?} returned for transaction {:?}",
+                                tr_receipt.transaction_hash, tr_hash,
+                            ))?;
+                        }
+                    }
+                }
+
+                for tr_receipt in tr_receipts.iter().flatten() {
+                    self.block_store.save_receipt(tr_receipt)?;
+                }
+
+                tr_receipts
+            };
+
+        Ok(tr_receipts)
+    }
+
+    /// Probes `eth_getBlockReceipts` once per connection
+    async fn is_block_receipts_supported(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let supported = self
+            .block_receipts_supported
+            .get_or_try_init(|| async {
+                self.limited("eth_getBlockReceipts (block latest)", || async {
+                    let supported = match self
+                        .web3
+                        .transport()
+                        .execute("eth_getBlockReceipts", vec![Value::from("latest")])
+                        .await
+                    {
+                        Ok(_) => true,
+                        Err(web3::Error::Rpc(e)) => {
+                            info!("eth_getBlockReceipts is not supported: {}", e.message);
+
+                            false
+                        }
+                        Err(e) => Err(e)?,
+                    };
+
+                    Ok(supported)
+                })
+                .await
+            })
+            .await?;
+
+        Ok(*supported)
+    }
+
+    /// Result: block with all its transactions, from DB or from server.
+    /// Block fetched from server is saved to DB.
+    /// Receipts are fetched and saved later, only of the desired transactions
+    async fn get_block(
+        &self,
+        block_number: U64,
+    ) -> Result<Block<Transaction>, Box<dyn std::error::Error>> {
+        if let Some(block) = self.block_store.get_block(block_number)? {
+            return Ok(block);
+        }
+
+        let block = self.get_block_with_transactions(block_number).await?;
+        self.block_store.save_block(&block)?;
+
+        Ok(block)
+    }
+
+    /// Block which server doesn't have yet (e.g. because it is behind the chain head)
+    /// is an error, so that the request is retried, probably by another provider
+    async fn get_block_with_transactions(
+        &self,
+        block_number: U64,
+    ) -> Result<Block<Transaction>, Box<dyn std::error::Error>> {
+        let block_id = BlockId::Number(BlockNumber::Number(block_number));
+
+        let block = self
+            .limited(
+                &format!("eth_getBlockByNumber (block {})", block_number),
+                || async {
+                    self.web3
+                        .eth()
+                        .block_with_txs(block_id)
+                        .await?
+                        .ok_or_else(Self::block_not_found)
+                },
+            )
+            .await;
+
+        let block = match block {
+            Ok(block) if Self::is_block_complete(&block) => block,
+            Ok(_) => {
+                warn!(
+                    "Block {} is truncated by server. Fetching transactions by hashes.",
+                    block_number,
+                );
+
+                self.get_block_with_transactions_by_hashes(block_number)
+                    .await?
+            }
+            Err(e) => {
+                warn!(
+                    "Block {} full fetch error: {}. Fetching transactions by hashes.",
+                    block_number, e,
+                );
+
+                self.get_block_with_transactions_by_hashes(block_number)
+                    .await?
+            }
+        };
+
+        self.block_timestamps
+            .save(block_number, block.timestamp.as_u64())?;
+
+        Ok(block)
+    }
+
+    /// Fallback for servers that fail or truncate full blocks:
+    /// fetches block with transaction hashes only and then every transaction by its hash
+    async fn get_block_with_transactions_by_hashes(
+        &self,
+        block_number: U64,
+    ) -> Result<Block<Transaction>, Box<dyn std::error::Error>> {
+        let block_id = BlockId::Number(BlockNumber::Number(block_number));
+
+        let block = self
+            .limited(
+                &format!("eth_getBlockByNumber (block {})", block_number),
+                || async {
+                    self.web3
+                        .eth()
+                        .block(block_id)
+                        .await?
+                        .ok_or_else(Self::block_not_found)
+                },
+            )
+            .await?;
+
+        let params = block
+            .transactions
+            .iter()
+            .map(|tr_hash| Ok(vec![serde_json::to_value(tr_hash)?]))
+            .collect::<Result<_, serde_json::Error>>()?;
+        let transactions: Vec<Transaction> = self
+            .batch_call(
+                "eth_getTransactionByHash",
+                block_number,
+                params,
+                Some(Self::transaction_not_found),
+            )
+            .await?;
+
+        // Same block, but with transactions instead of their hashes
+        let mut block = serde_json::to_value(block)?;
+        block["transactions"] = serde_json::to_value(transactions)?;
+
+        let block = serde_json::from_value(block)?;
+
+        Ok(block)
+    }
+
+    fn block_not_found() -> web3::Error {
+        web3::Error::InvalidResponse("Block is not found".to_string())
+    }
+
+    fn transaction_not_found() -> web3::Error {
+        web3::Error::InvalidResponse("Transaction is not found".to_string())
+    }
+
+    /// Result: `result`, or error of `not_found` if it is given and `result` is null
+    fn require_result(
+        result: Value,
+        not_found: Option<fn() -> web3::Error>,
+    ) -> web3::Result<Value> {
+        match not_found {
+            Some(not_found) if result.is_null() => Err(not_found()),
+            _ => Ok(result),
+        }
+    }
+
+    /// Every transaction uses no more gas than its gas limit,
+    /// so transactions of a complete block have total gas limit not less than block gas used
+    fn is_block_complete(block: &Block<Transaction>) -> bool {
+        let gas_limit_total = block
+            .transactions
+            .iter()
+            .fold(U256::zero(), |acc, transaction| {
+                acc.saturating_add(transaction.gas)
+            });
+
+        gas_limit_total >= block.gas_used
+    }
+
+    /// Sends `method` requests (one request per `params` item) as JSON RPC batches of `batch_size` requests.
+    /// Param `block_number` - block the requests are made for, used in errors
+    /// Param `not_found` - error of a null response, e.g. of an object which server doesn't have yet.
+    /// Such request is retried, probably by another provider. `None` - null response is valid
+    /// Result: responses in the same order as `params`
+    async fn batch_call<R: DeserializeOwned>(
+        &self,
+        method: &str,
+        block_number: U64,
+        params: Vec<Vec<Value>>,
+        not_found: Option<fn() -> web3::Error>,
+    ) -> Result<Vec<R>, Box<dyn std::error::Error>> {
+        let batches: Vec<_> = params.chunks(self.batch_size).map(<[_]>::to_vec).collect();
+
+        let results: Vec<Vec<Value>> = stream::iter(batches)
+            .map(|batch| self.send_batch(method, block_number, batch, not_found))
+            .buffered(self.max_concurrency)
+            .try_collect()
+            .await?;
+
+        let results = results
+            .into_iter()
+            .flatten()
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()?;
+
+        Ok(results)
+    }
+
+    /// Splits the batch in halves while server rejects it as a whole (e.g. because of its size)
+    /// and retries one by one requests that failed inside of the batch or got null of `not_found`
+    async fn send_batch(
+        &self,
+        method: &str,
+        block_number: U64,
+        params: Vec<Vec<Value>>,
+        not_found: Option<fn() -> web3::Error>,
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let transport = self.web3.transport();
+
+        let mut results = Vec::with_capacity(params.len());
+
+        // Stack of batches - the next batch to send is on top
+        let mut batches = vec![params];
+
+        while let Some(mut batch) = batches.pop() {
+            let requests: Vec<_> = batch
+                .iter()
+                .map(|params| transport.prepare(method, params.clone()))
+                .collect();
+
+            let batch_results = self
+                .limited(
+                    &format!(
+                        "{} batch of {} (block {})",
+                        method,
+                        batch.len(),
+                        block_number,
+                    ),
+                    || async {
+                        let batch_results = transport.send_batch(requests.clone()).await?;
+
+                        // Results are paired with requests by position
+                        if batch_results.len() != requests.len() {
+                            return Err(web3::Error::InvalidResponse(format!(
+                                "{} results of a batch of {} requests",
+                                batch_results.len(),
+                                requests.len(),
+                            )));
+                        }
+
+                        Ok(batch_results)
+                    },
+                )
+                .await;
+
+            match batch_results {
+                Ok(batch_results) => {
+                    for (params, result) in batch.into_iter().zip(batch_results) {
+                        let result = match result
+                            .and_then(|result| Self::require_result(result, not_found))
+                        {
+                            Ok(result) => result,
+                            Err(e) => {
+                                debug!("Batch request {} error: {}. Retrying it alone.", method, e);
+
+                                self.limited(
+                                    &format!("{} (block {})", method, block_number),
+                                    || async {
+                                        let result =
+                                            transport.execute(method, params.clone()).await?;
+
+                                        Self::require_result(result, not_found)
+                                    },
+                                )
+                                .await?
+                            }
+                        };
+
+                        results.push(result);
+                    }
+                }
+                Err(e) if batch.len() > 1 => {
+                    warn!(
+                        "Batch of {} requests rejected: {}. Splitting it.",
+                        batch.len(),
+                        e,
+                    );
+
+                    let batch_right = batch.split_off(batch.len() / 2);
+                    batches.push(batch_right);
+                    batches.push(batch);
+                }
+                Err(e) => Err(e)?,
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Runs `request` when the number of in-flight requests is below `max_concurrency`.
+    /// Failed `request` is retried by `retry_policy`, keeping its place among in-flight requests
+    /// Param `description` - call and its block, used in logs and in the final error
+    async fn limited<R, F, Fut>(
+        &self,
+        description: &str,
+        request: F,
+    ) -> Result<R, Box<dyn std::error::Error>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = web3::Result<R>>,
+    {
+        let _permit = self.requests_limiter.acquire().await?;
+
+        self.retry_policy
+            .call(description, || {
+                self.progress.add_request();
+
+                request()
+            })
+            .await
+    }
+
+    fn stringify_key(&self, account: Address, block_number: U64, tr_hash: H256) -> String {
+        // Add leading zeros to `block_number` string view
+        format!(
+            "{}_{:?}_{:0>32?}_{:?}",
+            self.chain_id, account, block_number, tr_hash,
+        )
+    }
+
+    fn stringify_token_key(&self, account: Address, token_transfer: &TokenTransfer) -> String {
+        // Add leading zeros to `block_number` and `log_index` string views
+        format!(
+            "{}_{:?}_{:0>32?}_{:0>32?}",
+            self.chain_id, account, token_transfer.block_number, token_transfer.log_index,
+        )
+    }
+
+    fn stringify_nft_key(&self, account: Address, nft_transfer: &NftTransfer) -> String {
+        // Add leading zeros to `block_number`, `log_index` and `batch_index` string views
+        format!(
+            "{}_{:?}_{:0>32?}_{:0>32?}_{:0>32?}",
+            self.chain_id,
+            account,
+            nft_transfer.block_number,
+            nft_transfer.log_index,
+            nft_transfer.batch_index,
+        )
+    }
+
+    fn stringify_internal_key(
+        &self,
+        account: Address,
+        internal_transaction: &InternalTransaction,
+    ) -> String {
+        let call_path: Vec<String> = internal_transaction
+            .call_path
+            .iter()
+            .map(usize::to_string)
+            .collect();
+
+        format!(
+            "{}_{}",
+            self.stringify_key(
+                account,
+                internal_transaction.block_number,
+                internal_transaction.transaction_hash,
+            ),
+            call_path.join("."),
+        )
+    }
+
+    fn stringify_key_range(
+        &self,
+        account: Address,
+        block_start: U64,
+        block_end: U64,
+    ) -> Range<String> {
+        // Add leading zeros to `block_number` string view
+        let begin = format!("{}_{:?}_{:0>32?}", self.chain_id, account, block_start);
+
+        // Add leading zeros to `block_number` string view
+        // Make max value for `block_number` - `tilda` char has biggest code in ASCII table
+        let end = format!("{}_{:?}_{:0>32?}_~~~", self.chain_id, account, block_end);
+
+        begin..end
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    /// Save token and NFT transfers to DB and mark blocks `block_start..=block_end` as scanned
+    /// for them for every account of `token_transfers` in one DB transaction.
+    /// Param `previous_block_end` - end of the range of the same scan saved before, it is replaced
+    fn cache_token_transfers(
+        &self,
+        token_transfers: &HashMap<Address, AccountTransactions>,
+        block_start: U64,
+        block_end: U64,
+        previous_block_end: Option<U64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut entries = Vec::new();
+        let mut nft_entries = Vec::new();
+        let mut scanned_range_entries = Vec::new();
+        let mut previous_scanned_range_keys = Vec::new();
+
+        for (account, account_token_transfers) in token_transfers {
+            for token_transfer in &account_token_transfers.token_transfers {
+                let key = self.stringify_token_key(*account, token_transfer);
+                debug!("key: {}", key);
+
+                // TODO: Remove json layer (serialize directly into bytes)
+                let bytes = serde_json::to_vec(token_transfer)?;
+
+                entries.push((key, bytes));
+            }
+
+            for nft_transfer in &account_token_transfers.nft_transfers {
+                let key = self.stringify_nft_key(*account, nft_transfer);
+                debug!("key: {}", key);
+
+                // TODO: Remove json layer (serialize directly into bytes)
+                let bytes = serde_json::to_vec(nft_transfer)?;
+
+                nft_entries.push((key, bytes));
+            }
+
+            let mut scanned_range = ScannedRange {
+                chain_id: self.chain_id,
+                account: *account,
+                block_start,
+                block_end,
+                traces: false,
+                nft_transfers: self.find_nft_transfers,
+                creations: false,
+                scanned_at: Utc::now().timestamp(),
+            };
+
+            scanned_range_entries.push((scanned_range.key(), serde_json::to_vec(&scanned_range)?));
+
+            if let Some(previous_block_end) = previous_block_end {
+                scanned_range.block_end = previous_block_end;
+
+                previous_scanned_range_keys.push(scanned_range.key());
+            }
+        }
+
+        let result: TransactionResult<(), sled::Error> = (
+            &self.token_transfers,
+            &self.nft_transfers,
+            &self.token_scanned_ranges,
+        )
+            .transaction(|(token_transfers, nft_transfers, token_scanned_ranges)| {
+                for (key, bytes) in &entries {
+                    token_transfers.insert(key.as_str(), bytes.as_slice())?;
+                }
+
+                for (key, bytes) in &nft_entries {
+                    nft_transfers.insert(key.as_str(), bytes.as_slice())?;
+                }
+
+                for key in &previous_scanned_range_keys {
+                    token_scanned_ranges.remove(key.as_str())?;
+                }
+
+                for (key, bytes) in &scanned_range_entries {
+                    token_scanned_ranges.insert(key.as_str(), bytes.as_slice())?;
+                }
+
+                Ok(())
+            });
+        result?;
+
+        let mut token_cache_keys = self.token_cache_keys.borrow_mut();
+        for account in token_transfers.keys() {
+            token_cache_keys
+                .entry(*account)
+                .or_default()
+                .insert(block_start.as_u64(), block_end.as_u64());
+        }
+
+        Ok(())
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    /// Save transactions to DB and mark blocks `block_start..=block_end` as scanned
+    /// for every account of `transactions` in one DB transaction.
+    /// Param `previous_block_end` - end of the range of the same scan saved before, it is replaced
+    fn cache_scanned_range(
+        &self,
+        transactions: &HashMap<Address, AccountTransactions>,
+        block_start: U64,
+        block_end: U64,
+        previous_block_end: Option<U64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut entries = Vec::new();
+        let mut internal_entries = Vec::new();
+        let mut scanned_range_entries = Vec::new();
+        let mut previous_scanned_range_keys = Vec::new();
+
+        for (account, account_transactions) in transactions {
+            for transaction in &account_transactions.transactions {
+                let key = self.stringify_key(
+                    *account,
+                    transaction.1.block_number.unwrap_or_default(),
+                    transaction.1.hash,
+                );
+                debug!("key: {}", key);
+
+                // TODO: Remove json layer (serialize directly into bytes)
+                let bytes = serde_json::to_vec(transaction)?;
+
+                entries.push((key, bytes));
+            }
+
+            for internal_transaction in &account_transactions.internal_transactions {
+                let key = self.stringify_internal_key(*account, internal_transaction);
+                debug!("key: {}", key);
+
+                // TODO: Remove json layer (serialize directly into bytes)
+                let bytes = serde_json::to_vec(internal_transaction)?;
+
+                internal_entries.push((key, bytes));
+            }
+
+            let mut scanned_range = ScannedRange {
+                chain_id: self.chain_id,
+                account: *account,
+                block_start,
+                block_end,
+                traces: self.traces.is_some(),
+                nft_transfers: false,
+                creations: true,
+                scanned_at: Utc::now().timestamp(),
+            };
+
+            scanned_range_entries.push((scanned_range.key(), serde_json::to_vec(&scanned_range)?));
+
+            if let Some(previous_block_end) = previous_block_end {
+                scanned_range.block_end = previous_block_end;
+
+                previous_scanned_range_keys.push(scanned_range.key());
+            }
+        }
+
+        let result: TransactionResult<(), sled::Error> =
+            (&*self.db, &self.internal_transactions, &self.scanned_ranges).transaction(
+                |(db, internal_transactions, scanned_ranges)| {
+                    for (key, bytes) in &entries {
+                        db.insert(key.as_str(), bytes.as_slice())?;
+                    }
+
+                    for (key, bytes) in &internal_entries {
+                        internal_transactions.insert(key.as_str(), bytes.as_slice())?;
+                    }
+
+                    for key in &previous_scanned_range_keys {
+                        scanned_ranges.remove(key.as_str())?;
+                    }
+
+                    for (key, bytes) in &scanned_range_entries {
+                        scanned_ranges.insert(key.as_str(), bytes.as_slice())?;
+                    }
+
+                    Ok(())
+                },
+            );
+        result?;
+
+        let mut cache_keys = self.cache_keys.borrow_mut();
+        for account in transactions.keys() {
+            cache_keys
+                .entry(*account)
+                .or_default()
+                .insert(block_start.as_u64(), block_end.as_u64());
+        }
 
         Ok(())
     }
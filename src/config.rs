@@ -1,14 +1,71 @@
-use chrono::{DateTime, NaiveDate, ParseError, Utc};
-use clap::{Arg, ArgMatches, Command, ValueHint};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, ParseError, Utc};
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command, ValueHint};
+use std::fs;
 use std::str::FromStr;
 
+/// Cutoff of blocks which are considered immutable. Blocks above it are verified for reorgs
+#[derive(Clone, Debug)]
+pub enum Confirmations {
+    /// Number of blocks below the chain head
+    Depth(u64),
+    /// `safe` or `finalized` block of the server
+    Tag(String),
+}
+
+/// JSON RPC API of traces used to find internal transactions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Traces {
+    /// `trace_block`
+    Parity,
+    /// `debug_traceBlockByNumber` with `callTracer`
+    Geth,
+}
+
+/// Period of the balance series
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeriesInterval {
+    Day,
+    /// Week ends on Sunday
+    Week,
+    Month,
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
-    pub jsonrpc_url: Option<String>,
-    pub account: String,
+    /// Empty means default
+    pub jsonrpc_urls: Vec<String>,
+    pub accounts: Vec<String>,
+    /// `0` for subcommands
     pub block_start: u64,
     pub block_end: Option<u64>,
     pub timestamp: Option<DateTime<Utc>>,
+    /// Tokens of the portfolio snapshot. Empty means all ERC-20 tokens of the account transfers
+    pub balance_tokens: Vec<String>,
+    /// (first day, last day) of the balance series. `None` means no series
+    pub series: Option<(NaiveDate, NaiveDate)>,
+    pub series_interval: SeriesInterval,
+    pub max_concurrency: usize,
+    pub batch_size: usize,
+    pub checkpoint_interval: u64,
+    pub rpc_retries: u32,
+    /// Seconds
+    pub rpc_timeout: u64,
+    /// Seconds. `None` means no deadline
+    pub crawl_deadline: Option<u64>,
+    pub quorum: usize,
+    pub confirmations: Confirmations,
+    /// `None` means internal transactions are not searched
+    pub traces: Option<Traces>,
+    pub token_transfers: bool,
+    pub nft_transfers: bool,
+    /// Max number of blocks in one `eth_getLogs` query
+    pub logs_range: u64,
+    pub follow: bool,
+    /// `None` means stdout
+    pub follow_output: Option<String>,
+    /// Datetime of the `block-at` subcommand, which prints its block instead of the crawl.
+    /// `None` means crawl
+    pub block_at: Option<DateTime<Utc>>,
 }
 
 impl Config {
@@ -16,9 +73,16 @@ impl Config {
         let matches = Self::make_matches();
 
         Ok(Self {
-            jsonrpc_url: matches.get_one("jsonrpc_url").cloned(),
-            account: matches.get_one("account").cloned().unwrap(),
-            block_start: matches.value_of("block_start").unwrap().parse()?,
+            jsonrpc_urls: matches
+                .get_many("jsonrpc_url")
+                .map(|v| v.cloned().collect())
+                .unwrap_or_default(),
+            accounts: Self::accounts_from_matches(&matches)?,
+            block_start: matches
+                .value_of("block_start")
+                .map(u64::from_str)
+                .transpose()?
+                .unwrap_or_default(),
             block_end: matches
                 .value_of("block_end")
                 .map(u64::from_str)
@@ -27,6 +91,44 @@ impl Config {
                 .value_of("timestamp")
                 .map(Self::date_time_from_string)
                 .transpose()?,
+            balance_tokens: matches
+                .get_many("balance_token")
+                .map(|v| v.cloned().collect())
+                .unwrap_or_default(),
+            series: Self::series_from_matches(&matches)?,
+            series_interval: match matches.value_of("series_interval").unwrap() {
+                "day" => SeriesInterval::Day,
+                "week" => SeriesInterval::Week,
+                _ => SeriesInterval::Month,
+            },
+            max_concurrency: matches.value_of("max_concurrency").unwrap().parse()?,
+            batch_size: matches.value_of("batch_size").unwrap().parse()?,
+            checkpoint_interval: matches.value_of("checkpoint_interval").unwrap().parse()?,
+            rpc_retries: matches.value_of("rpc_retries").unwrap().parse()?,
+            rpc_timeout: matches.value_of("rpc_timeout").unwrap().parse()?,
+            crawl_deadline: matches
+                .value_of("crawl_deadline")
+                .map(u64::from_str)
+                .transpose()?,
+            quorum: matches.value_of("quorum").unwrap().parse()?,
+            confirmations: Self::confirmations_from_string(
+                matches.value_of("confirmations").unwrap(),
+            )?,
+            traces: matches
+                .get_one::<String>("traces")
+                .map(|v| match v.as_str() {
+                    "parity" => Traces::Parity,
+                    _ => Traces::Geth,
+                }),
+            token_transfers: *matches.get_one::<bool>("token_transfers").unwrap(),
+            nft_transfers: *matches.get_one::<bool>("nft_transfers").unwrap(),
+            logs_range: matches.value_of("logs_range").unwrap().parse()?,
+            follow: *matches.get_one::<bool>("follow").unwrap(),
+            follow_output: matches.get_one("follow_output").cloned(),
+            block_at: matches
+                .subcommand_matches("block-at")
+                .map(|v| Self::date_time_from_datetime_string(v.value_of("datetime").unwrap()))
+                .transpose()?,
         })
     }
 
@@ -34,18 +136,44 @@ impl Config {
     fn make_matches() -> ArgMatches {
         Command::new("Eth crawler")
             .version("1.0")
+            // Subcommands don't crawl, so they need no accounts and blocks
+            .subcommand_negates_reqs(true)
+            .subcommand(
+                Command::new("block-at")
+                    .about("Print the first block not earlier than the datetime, using the index of known block timestamps")
+                    .arg(
+                        Arg::new("datetime")
+                            .value_name("DATETIME")
+                            .help("UTC datetime in YYYY-MM-DD, YYYY-MM-DDTHH:MM:SS or RFC 3339 format")
+                            .required(true),
+                    ),
+            )
             .arg(
                 Arg::new("jsonrpc_url")
                     .long("jsonrpc_url")
                     .value_name("URL")
-                    .help("Ethereum JSON RPC url")
-                    .value_hint(ValueHint::Url),
+                    .help("Ethereum JSON RPC url with optional `;weight=W;concurrency=C` suffix. Can be passed multiple times")
+                    .value_hint(ValueHint::Url)
+                    .action(ArgAction::Append),
             )
             .arg(
                 Arg::new("account")
                     .long("account")
                     .value_name("ACCOUNT")
-                    .help("Ethereum account address")
+                    .help("Ethereum account address. Can be passed multiple times")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("accounts_file")
+                    .long("accounts_file")
+                    .value_name("PATH")
+                    .help("File with Ethereum account addresses, one per line")
+                    .value_hint(ValueHint::FilePath),
+            )
+            .group(
+                ArgGroup::new("accounts")
+                    .args(&["account", "accounts_file"])
+                    .multiple(true)
                     .required(true),
             )
             .arg(
@@ -67,9 +195,213 @@ impl Config {
                     .value_name("YYYY-MM-DD")
                     .help("Timestamp in YYYY-MM-DD format to fetch account balance"),
             )
+            .arg(
+                Arg::new("balance_token")
+                    .long("balance_token")
+                    .value_name("TOKEN")
+                    .help("ERC-20 token address of the balance snapshot. Can be passed multiple times. Default: tokens transferred from block 0 up to the balance block")
+                    .action(ArgAction::Append)
+                    .requires("timestamp"),
+            )
+            .arg(
+                Arg::new("series_from")
+                    .long("series_from")
+                    .value_name("YYYY-MM-DD")
+                    .help("First day of the balance series")
+                    .requires("series_to"),
+            )
+            .arg(
+                Arg::new("series_to")
+                    .long("series_to")
+                    .value_name("YYYY-MM-DD")
+                    .help("Last day of the balance series")
+                    .requires("series_from"),
+            )
+            .arg(
+                Arg::new("series_interval")
+                    .long("series_interval")
+                    .value_name("day|week|month")
+                    .help("Balance series has a balance at the end of every period of this length")
+                    .value_parser(["day", "week", "month"])
+                    .default_value("day"),
+            )
+            .arg(
+                Arg::new("max_concurrency")
+                    .long("max_concurrency")
+                    .value_name("MAX_CONCURRENCY")
+                    .help("Max in-flight requests per JSON RPC provider (unsigned integer)")
+                    .default_value("16"),
+            )
+            .arg(
+                Arg::new("batch_size")
+                    .long("batch_size")
+                    .value_name("BATCH_SIZE")
+                    .help("Max number of requests in one JSON RPC batch (unsigned integer)")
+                    .default_value("50"),
+            )
+            .arg(
+                Arg::new("checkpoint_interval")
+                    .long("checkpoint_interval")
+                    .value_name("BLOCKS")
+                    .help("Number of blocks between saves of scan progress (unsigned integer)")
+                    .default_value("1000"),
+            )
+            .arg(
+                Arg::new("rpc_retries")
+                    .long("rpc_retries")
+                    .value_name("RPC_RETRIES")
+                    .help("Max number of retries of a failed JSON RPC call (unsigned integer)")
+                    .default_value("5"),
+            )
+            .arg(
+                Arg::new("rpc_timeout")
+                    .long("rpc_timeout")
+                    .value_name("SECONDS")
+                    .help("Timeout of one JSON RPC call in seconds (unsigned integer)")
+                    .default_value("30"),
+            )
+            .arg(
+                Arg::new("crawl_deadline")
+                    .long("crawl_deadline")
+                    .value_name("SECONDS")
+                    .help("Max duration of the whole run in seconds (unsigned integer)"),
+            )
+            .arg(
+                Arg::new("quorum")
+                    .long("quorum")
+                    .value_name("N")
+                    .help("Number of JSON RPC providers which must return the same data (unsigned integer)")
+                    .default_value("1"),
+            )
+            .arg(
+                Arg::new("confirmations")
+                    .long("confirmations")
+                    .value_name("N|safe|finalized")
+                    .help("Blocks deeper than N blocks below the head (unsigned integer), or below the `safe` or `finalized` block, are considered immutable")
+                    .default_value("12"),
+            )
+            .arg(
+                Arg::new("traces")
+                    .long("traces")
+                    .value_name("parity|geth")
+                    .help("Also find ETH transfers made by contract calls, with `trace_block` (parity) or `debug_traceBlockByNumber` (geth)")
+                    .value_parser(["parity", "geth"]),
+            )
+            .arg(
+                Arg::new("token_transfers")
+                    .long("token_transfers")
+                    .help("Also find ERC-20 token transfers with `eth_getLogs`")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("nft_transfers")
+                    .long("nft_transfers")
+                    .help("Also find ERC-721 and ERC-1155 NFT transfers with `eth_getLogs`")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("logs_range")
+                    .long("logs_range")
+                    .value_name("BLOCKS")
+                    .help("Max number of blocks in one `eth_getLogs` query. Ranges with too many logs are split automatically (unsigned integer)")
+                    .default_value("2000"),
+            )
+            .arg(
+                Arg::new("follow")
+                    .long("follow")
+                    .help("After the scan, keep processing new blocks until Ctrl-C")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("block_end"),
+            )
+            .arg(
+                Arg::new("follow_output")
+                    .long("follow_output")
+                    .value_name("PATH")
+                    .help("File to append transactions found in follow mode to, as JSON lines. Default: stdout")
+                    .value_hint(ValueHint::FilePath)
+                    .requires("follow"),
+            )
             .get_matches()
     }
 
+    /// Result: accounts from `--account` params followed by accounts from `--accounts_file`
+    fn accounts_from_matches(
+        matches: &ArgMatches,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut accounts: Vec<String> = matches
+            .get_many("account")
+            .map(|v| v.cloned().collect())
+            .unwrap_or_default();
+
+        if let Some(accounts_file) = matches.get_one::<String>("accounts_file") {
+            let file_string = fs::read_to_string(accounts_file)
+                .map_err(|e| format!("Accounts file read error: {}", e))?;
+
+            // Empty lines and `#` comments are skipped
+            let file_accounts = file_string
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty())
+                .map(str::to_string);
+
+            accounts.extend(file_accounts);
+        }
+
+        Ok(accounts)
+    }
+
+    fn confirmations_from_string(
+        confirmations: &str,
+    ) -> Result<Confirmations, Box<dyn std::error::Error>> {
+        let confirmations =
+            match confirmations {
+                "safe" | "finalized" => Confirmations::Tag(confirmations.to_string()),
+                _ => Confirmations::Depth(confirmations.parse().map_err(|_| {
+                    "Confirmations must be an unsigned integer, `safe` or `finalized`"
+                })?),
+            };
+
+        Ok(confirmations)
+    }
+
+    /// Result: (first day, last day) of the balance series
+    fn series_from_matches(
+        matches: &ArgMatches,
+    ) -> Result<Option<(NaiveDate, NaiveDate)>, Box<dyn std::error::Error>> {
+        let (from, to) = match (
+            matches.value_of("series_from"),
+            matches.value_of("series_to"),
+        ) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Ok(None),
+        };
+
+        let from = NaiveDate::parse_from_str(from, "%Y-%m-%d")?;
+        let to = NaiveDate::parse_from_str(to, "%Y-%m-%d")?;
+
+        if from > to {
+            Err("Balance series must not end before its start")?;
+        }
+
+        Ok(Some((from, to)))
+    }
+
+    /// Param `datetime` - RFC 3339, or UTC in `YYYY-MM-DDTHH:MM:SS` or `YYYY-MM-DD` format
+    fn date_time_from_datetime_string(
+        datetime: &str,
+    ) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+        if let Ok(datetime) = DateTime::parse_from_rfc3339(datetime) {
+            return Ok(datetime.with_timezone(&Utc));
+        }
+
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M:%S") {
+            return Ok(DateTime::<Utc>::from_utc(datetime, Utc));
+        }
+
+        Self::date_time_from_string(datetime)
+            .map_err(|_| format!("Datetime parse error: {}", datetime).into())
+    }
+
     fn date_time_from_string(timestamp: &str) -> Result<DateTime<Utc>, ParseError> {
         let timestamp = NaiveDate::parse_from_str(timestamp, "%Y-%m-%d")?;
         let timestamp = timestamp.and_hms(0, 0, 0);
@@ -1,23 +1,201 @@
 use crate::constants::JSONRPC_URLS;
-use web3::transports::Http;
+use crate::transports::endpoint::Endpoint;
+use crate::transports::pool::{Provider, ProviderPool, MAX_HEAD_LAG};
+use futures::future;
+use std::time::{Duration, Instant};
+use web3::types::{U256, U64};
 use web3::Web3;
 
-/// Param `jsonrpc_url` - `None` means default
-pub fn try_connect(jsonrpc_url: Option<String>) -> Result<Web3<Http>, Box<dyn std::error::Error>> {
-    let urls = jsonrpc_url
-        .as_ref()
-        .map(|v| vec![v.as_str()])
-        .unwrap_or(JSONRPC_URLS.to_vec());
+/// Url with options of a provider
+struct ProviderUrl {
+    url: String,
+    weight: u32,
+    concurrency: usize,
+}
+
+/// Result of the startup health check of a provider
+struct Probe {
+    chain_id: U256,
+    head: U64,
+    /// Duration of `eth_blockNumber` call
+    latency: Duration,
+}
+
+/// Probes every url and connects to all of them that respond on the same chain.
+/// Requests are spread across healthy providers
+/// Param `jsonrpc_urls` - `URL[;weight=W][;concurrency=C]` items. Empty means default
+/// Param `max_concurrency` - default concurrency of a provider
+/// Param `timeout` - timeout of every request
+/// Param `quorum` - number of providers which must return the same data. `1` means no verification
+pub async fn try_connect(
+    jsonrpc_urls: &[String],
+    max_concurrency: usize,
+    timeout: Duration,
+    quorum: usize,
+) -> Result<Web3<ProviderPool>, Box<dyn std::error::Error>> {
+    if max_concurrency == 0 {
+        Err("Max concurrency must be greater than zero")?;
+    }
+    if quorum == 0 {
+        Err("Quorum must be greater than zero")?;
+    }
+    if timeout.is_zero() {
+        Err("RPC timeout must be greater than zero")?;
+    }
+
+    let urls = if jsonrpc_urls.is_empty() {
+        JSONRPC_URLS
+            .iter()
+            .map(|url| ProviderUrl {
+                url: url.to_string(),
+                weight: 1,
+                concurrency: max_concurrency,
+            })
+            .collect()
+    } else {
+        jsonrpc_urls
+            .iter()
+            .map(|url| parse_provider_url(url, max_concurrency))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    let probes = future::join_all(urls.iter().map(|url| probe(&url.url, timeout))).await;
+
+    let mut providers = Vec::new();
+    for (url, probe) in urls.into_iter().zip(probes) {
+        let name = provider_name(&url.url);
+
+        match probe {
+            Ok((transport, probe)) => {
+                info!(
+                    "Provider {}. Chain id: {}. Head: {}. Latency: {:?}.",
+                    name, probe.chain_id, probe.head, probe.latency,
+                );
 
-    let mut errors = Vec::new();
-    let transport = urls
+                providers.push((name, url, transport, probe));
+            }
+            Err(e) => warn!("Provider {} is unavailable: {}", name, e),
+        }
+    }
+
+    // Chain is chosen by the first available url
+    let chain_id = providers
+        .first()
+        .map(|(_, _, _, probe)| probe.chain_id)
+        .ok_or("No available JSON RPC provider")?;
+
+    providers.retain(|(name, _, _, probe)| {
+        if probe.chain_id != chain_id {
+            warn!(
+                "Provider {} is skipped. Chain id: {}. Expected chain id: {}.",
+                name, probe.chain_id, chain_id,
+            );
+        }
+
+        probe.chain_id == chain_id
+    });
+
+    let best_head = providers
+        .iter()
+        .map(|(_, _, _, probe)| probe.head)
+        .max()
+        .unwrap_or_default();
+
+    let providers: Vec<Provider> = providers
         .into_iter()
-        .map(Http::new)
-        .find_map(|r| r.map_err(|e| errors.push(e)).ok());
+        .map(|(name, url, transport, probe)| {
+            let healthy = probe.head.as_u64() + MAX_HEAD_LAG >= best_head.as_u64();
+            if !healthy {
+                warn!(
+                    "Provider {} is behind the chain head. Head: {}. Best head: {}.",
+                    name, probe.head, best_head,
+                );
+            }
+
+            Provider::new(
+                name,
+                transport,
+                url.weight,
+                url.concurrency,
+                healthy,
+                probe.head.as_u64(),
+            )
+        })
+        .collect();
+
+    if providers.len() < quorum {
+        Err(format!(
+            "Quorum of {} needs at least {} available providers, got {}",
+            quorum,
+            quorum,
+            providers.len(),
+        ))?;
+    }
 
-    let web3 = transport
-        .map(Web3::new)
-        .ok_or_else(|| errors.swap_remove(0))?;
+    let transport = ProviderPool::new(providers, quorum);
+    transport.watch_heads();
+
+    Ok(Web3::new(transport))
+}
+
+async fn probe(
+    url: &str,
+    timeout: Duration,
+) -> Result<(Endpoint, Probe), Box<dyn std::error::Error>> {
+    let web3 = Web3::new(Endpoint::connect(url, timeout).await?);
+
+    let chain_id = web3.eth().chain_id().await?;
+
+    let started_at = Instant::now();
+    let head = web3.eth().block_number().await?;
+    let latency = started_at.elapsed();
+
+    let probe = Probe {
+        chain_id,
+        head,
+        latency,
+    };
+
+    Ok((web3.transport().clone(), probe))
+}
+
+/// Param `url` - `URL[;weight=W][;concurrency=C]`
+fn parse_provider_url(
+    url: &str,
+    max_concurrency: usize,
+) -> Result<ProviderUrl, Box<dyn std::error::Error>> {
+    let mut parts = url.split(';');
+
+    let mut provider_url = ProviderUrl {
+        url: parts.next().unwrap_or_default().to_string(),
+        weight: 1,
+        concurrency: max_concurrency,
+    };
+
+    for option in parts {
+        match option.split_once('=') {
+            Some(("weight", weight)) => provider_url.weight = weight.parse()?,
+            Some(("concurrency", concurrency)) => provider_url.concurrency = concurrency.parse()?,
+            _ => Err(format!("Unknown JSON RPC url option: {}", option))?,
+        }
+    }
+
+    if provider_url.weight == 0 || provider_url.concurrency == 0 {
+        Err("Weight and concurrency of JSON RPC url must be greater than zero")?;
+    }
+
+    Ok(provider_url)
+}
 
-    Ok(web3)
+/// Result: host and port of `url` (IPC path as is). Path and query may contain an API key,
+/// so they are skipped
+fn provider_name(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => url.scheme().to_string(),
+        },
+        Err(_) => url.to_string(),
+    }
 }
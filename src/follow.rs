@@ -0,0 +1,146 @@
+use crate::cached_transactions::TransactionWithReceipt;
+use crate::rpc::RetryPolicy;
+use futures::StreamExt;
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::time::Duration;
+use tokio::sync::watch;
+use web3::transports::WebSocket;
+use web3::types::{Address, U64};
+use web3::{Transport, Web3};
+
+/// How often `eth_blockNumber` is polled when there is no `newHeads` subscription
+const POLL_INTERVAL: Duration = Duration::from_secs(4);
+
+/// Result: receiver of the latest chain head number.
+/// Heads come from `newHeads` subscription over the first WebSocket url,
+/// or from polling `eth_blockNumber` when there is no such url or the subscription fails
+pub async fn watch_new_heads<T>(
+    web3: Web3<T>,
+    retry_policy: &RetryPolicy,
+    jsonrpc_urls: &[String],
+) -> Result<watch::Receiver<U64>, Box<dyn std::error::Error>>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    let head = retry_policy
+        .call("eth_blockNumber", || web3.eth().block_number())
+        .await?;
+    let (sender, receiver) = watch::channel(head);
+
+    // Url options (`;weight=W`) are not a part of the url
+    let ws_url = jsonrpc_urls
+        .iter()
+        .map(|url| url.split(';').next().unwrap_or_default())
+        .find(|url| url.starts_with("ws://") || url.starts_with("wss://"))
+        .map(str::to_string);
+
+    tokio::spawn(async move {
+        if let Some(ws_url) = ws_url {
+            if let Err(e) = subscribe_new_heads(&ws_url, &sender).await {
+                warn!(
+                    "New heads subscription error: {}. Polling eth_blockNumber.",
+                    e
+                );
+            }
+        }
+
+        poll_new_heads(web3, &sender).await;
+    });
+
+    Ok(receiver)
+}
+
+async fn subscribe_new_heads(url: &str, sender: &watch::Sender<U64>) -> web3::Result<()> {
+    let web3 = Web3::new(WebSocket::new(url).await?);
+    let mut heads = web3.eth_subscribe().subscribe_new_heads().await?;
+
+    info!("Subscribed to new heads.");
+
+    while let Some(head) = heads.next().await {
+        if let Some(number) = head?.number {
+            send_head(sender, number);
+        }
+    }
+
+    Err(web3::Error::Unreachable)
+}
+
+async fn poll_new_heads<T: Transport>(web3: Web3<T>, sender: &watch::Sender<U64>) {
+    while !sender.is_closed() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        match web3.eth().block_number().await {
+            Ok(number) => send_head(sender, number),
+            Err(e) => warn!("New head poll error: {}", e),
+        }
+    }
+}
+
+/// Heads are only moved forward. Reorged heads come with a new number anyway
+fn send_head(sender: &watch::Sender<U64>, number: U64) {
+    sender.send_if_modified(|head| {
+        let is_new = number > *head;
+        if is_new {
+            *head = number;
+        }
+
+        is_new
+    });
+}
+
+/// Destination of transactions found in follow mode. One JSON object per line
+pub struct FollowOutput {
+    /// `None` means stdout
+    file: Option<File>,
+}
+
+impl FollowOutput {
+    /// Param `path` - file to append to. `None` means stdout
+    pub fn new(path: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = path
+            .map(|path| OpenOptions::new().create(true).append(true).open(path))
+            .transpose()
+            .map_err(|e| format!("Follow output file open error: {}", e))?;
+
+        Ok(Self { file })
+    }
+
+    pub fn write(
+        &mut self,
+        account: Address,
+        transaction: &TransactionWithReceipt,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (timestamp, transaction, tr_receipt) = transaction;
+
+        info!(
+            "New transaction. Account: {:?}. Block: {}. Hash: {:?}.",
+            account,
+            transaction.block_number.unwrap_or_default(),
+            transaction.hash,
+        );
+
+        let line = json!({
+            "account": account,
+            "block_number": transaction.block_number,
+            "block_hash": transaction.block_hash,
+            "timestamp": timestamp,
+            "transaction_hash": transaction.hash,
+            "from": transaction.from,
+            "to": transaction.to,
+            "contract_address": tr_receipt.as_ref().and_then(|v| v.contract_address),
+            "value": transaction.value,
+            "status": tr_receipt.as_ref().and_then(|v| v.status),
+        })
+        .to_string();
+
+        match &mut self.file {
+            Some(file) => writeln!(file, "{}", line)?,
+            None => writeln!(io::stdout(), "{}", line)?,
+        }
+
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
 pub mod file;
 pub mod render;
+pub mod report;
 pub mod templates;
@@ -1,41 +1,120 @@
+use crate::balance::{BalancePoint, Portfolio};
+use crate::cached_transactions::TransactionWithReceipt;
+use crate::html::report::AccountReport;
 use crate::html::templates::{
-    BALANCE_HTML, DATA_CELL_HTML, HEADER_CELL_HTML, RESULTS_HTML, ROW_HTML,
+    ACCOUNT_HTML, BALANCE_SERIES_HTML, CHART_SVG, DATA_CELL_HTML, HEADER_CELL_HTML,
+    INTERNAL_TRANSACTIONS_HTML, NFT_TRANSFERS_HTML, PORTFOLIO_HTML, RESULTS_HTML, ROW_HTML,
+    TOKEN_TRANSFERS_HTML,
 };
+use crate::token_metadata::TokenMetadata;
+use crate::tokens::{NftTransfer, TokenTransfer};
+use crate::traces::InternalTransaction;
 use chrono::{DateTime, NaiveDateTime, Utc};
-use web3::types::{Transaction, TransactionReceipt, U256};
+use std::collections::HashMap;
+use web3::types::Address;
+
+pub fn render_html(reports: Vec<AccountReport>) -> Result<String, Box<dyn std::error::Error>> {
+    let mut accounts = String::new();
+
+    for report in reports {
+        accounts.push_str(&render_account(report)?);
+    }
 
-pub fn render_html(
-    account: String,
-    transactions: Vec<(u64, Transaction, Option<TransactionReceipt>)>,
-    balance: Option<(DateTime<Utc>, U256)>,
-) -> Result<String, Box<dyn std::error::Error>> {
     let htmp_string = RESULTS_HTML;
-    let htmp_string = htmp_string.replace("{account}", &account);
-    let htmp_string = htmp_string.replace("{balance}", &render_balance(balance)?);
-    let htmp_string = htmp_string.replace("{rows}", &render_rows(transactions)?);
+    let htmp_string = htmp_string.replace("{accounts}", &accounts);
 
     Ok(htmp_string)
 }
 
-pub fn render_balance(
-    balance: Option<(DateTime<Utc>, U256)>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let html_string = if let Some((timestamp, balance)) = balance {
-        let html_string = BALANCE_HTML;
-        let html_string = html_string.replace("{timestamp}", &timestamp.to_string());
-        let html_string =
-            html_string.replace("{balance}", &format!("{}", balance.as_u128() as f64 / 1e18));
+fn render_account(report: AccountReport) -> Result<String, Box<dyn std::error::Error>> {
+    let html_string = ACCOUNT_HTML;
+    let html_string = html_string.replace("{account}", &format!("{:?}", report.account));
+    let html_string = html_string.replace(
+        "{portfolio}",
+        &render_portfolio(report.portfolio, &report.token_metadata)?,
+    );
+    let html_string = html_string.replace(
+        "{balance_series}",
+        &render_balance_series(report.balance_series)?,
+    );
+    let html_string = html_string.replace("{rows}", &render_rows(report.transactions)?);
+    let html_string = html_string.replace(
+        "{internal_transactions}",
+        &render_internal_transactions(report.internal_transactions)?,
+    );
+    let html_string = html_string.replace(
+        "{token_transfers}",
+        &render_token_transfers(report.token_transfers, &report.token_metadata)?,
+    );
+    let html_string = html_string.replace(
+        "{nft_transfers}",
+        &render_nft_transfers(report.account, report.nft_transfers, &report.token_metadata)?,
+    );
 
-        html_string
-    } else {
-        "".to_string()
+    Ok(html_string)
+}
+
+pub fn render_portfolio(
+    portfolio: Option<Portfolio>,
+    token_metadata: &HashMap<Address, TokenMetadata>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let portfolio = match portfolio {
+        Some(portfolio) => portfolio,
+        None => return Ok("".to_string()),
     };
 
+    let mut rows = String::new();
+
+    // Header row
+    let header_cells = ["asset", "balance"];
+
+    let mut row = String::new();
+    for header_cell in header_cells {
+        row.push_str(&HEADER_CELL_HTML.replace("{data}", header_cell));
+    }
+    rows.push_str(&ROW_HTML.replace("{cells}", &row));
+
+    let eth_cells = [
+        "ETH".to_string(),
+        format!("{}", portfolio.balance.as_u128() as f64 / 1e18),
+    ];
+    let token_cells = portfolio
+        .token_balances
+        .into_iter()
+        .map(|(token, balance)| {
+            let balance = match balance {
+                Some(balance) => escape_html(
+                    &token_metadata
+                        .get(&token)
+                        .cloned()
+                        .unwrap_or_default()
+                        .format_amount(balance),
+                ),
+                // E.g. the token wasn't deployed yet
+                None => "unknown".to_string(),
+            };
+
+            [render_token(token, token_metadata), balance]
+        });
+
+    for cells in std::iter::once(eth_cells).chain(token_cells) {
+        let mut row = String::new();
+        for cell in cells {
+            row.push_str(&DATA_CELL_HTML.replace("{data}", &cell));
+        }
+        rows.push_str(&ROW_HTML.replace("{cells}", &row));
+    }
+
+    let html_string = PORTFOLIO_HTML;
+    let html_string = html_string.replace("{timestamp}", &portfolio.timestamp.to_string());
+    let html_string = html_string.replace("{block_number}", &portfolio.block_number.to_string());
+    let html_string = html_string.replace("{rows}", &rows);
+
     Ok(html_string)
 }
 
 fn render_rows(
-    transactions: Vec<(u64, Transaction, Option<TransactionReceipt>)>,
+    transactions: Vec<TransactionWithReceipt>,
 ) -> Result<String, Box<dyn std::error::Error>> {
     let mut rows = String::new();
 
@@ -60,6 +139,7 @@ fn render_header_row() -> Result<String, Box<dyn std::error::Error>> {
         "timestamp",
         "from",
         "to",
+        "created contract",
         "value",
         "gas price",
         "gas used",
@@ -78,9 +158,7 @@ fn render_header_row() -> Result<String, Box<dyn std::error::Error>> {
     Ok(row)
 }
 
-fn render_row(
-    transaction: (u64, Transaction, Option<TransactionReceipt>),
-) -> Result<String, Box<dyn std::error::Error>> {
+fn render_row(transaction: TransactionWithReceipt) -> Result<String, Box<dyn std::error::Error>> {
     let mut row = String::new();
 
     // *******************************************************************************************************************
@@ -114,9 +192,27 @@ fn render_row(
 
     // *******************************************************************************************************************
 
+    // Deployment has no receiver
     let cell = DATA_CELL_HTML.replace(
         "{data}",
-        &format!("{:?}", transaction.1.to.unwrap_or_default()),
+        &transaction
+            .1
+            .to
+            .map(|v| format!("{:?}", v))
+            .unwrap_or_else(|| "contract creation".to_string()),
+    );
+    row.push_str(&cell);
+
+    // *******************************************************************************************************************
+
+    let cell = DATA_CELL_HTML.replace(
+        "{data}",
+        &transaction
+            .2
+            .as_ref()
+            .and_then(|v| v.contract_address)
+            .map(|v| format!("{:?}", v))
+            .unwrap_or_default(),
     );
     row.push_str(&cell);
 
@@ -183,8 +279,355 @@ fn render_row(
     Ok(row)
 }
 
+fn render_internal_transactions(
+    internal_transactions: Vec<InternalTransaction>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if internal_transactions.is_empty() {
+        return Ok("".to_string());
+    }
+
+    let mut rows = String::new();
+
+    // Header row
+    let header_cells = [
+        "transaction hash",
+        "block number",
+        "timestamp",
+        "call path",
+        "call type",
+        "from",
+        "to",
+        "value",
+    ];
+
+    let mut row = String::new();
+    for header_cell in header_cells {
+        row.push_str(&HEADER_CELL_HTML.replace("{data}", header_cell));
+    }
+    rows.push_str(&ROW_HTML.replace("{cells}", &row));
+
+    for internal_transaction in internal_transactions {
+        // Call path `0.2` is the third subcall of the first subcall of the transaction call
+        let call_path: Vec<String> = internal_transaction
+            .call_path
+            .iter()
+            .map(usize::to_string)
+            .collect();
+
+        let cells = [
+            format!("{:?}", internal_transaction.transaction_hash),
+            format!("{:?}", internal_transaction.block_number),
+            date_time_from_timestamp_sec(internal_transaction.timestamp).to_string(),
+            call_path.join("."),
+            internal_transaction.call_type,
+            format!("{:?}", internal_transaction.from),
+            format!("{:?}", internal_transaction.to),
+            format!("{} ETH", internal_transaction.value.as_u128() as f64 / 1e18),
+        ];
+
+        let mut row = String::new();
+        for cell in cells {
+            row.push_str(&DATA_CELL_HTML.replace("{data}", &cell));
+        }
+        rows.push_str(&ROW_HTML.replace("{cells}", &row));
+    }
+
+    let html_string = INTERNAL_TRANSACTIONS_HTML.replace("{rows}", &rows);
+
+    Ok(html_string)
+}
+
+fn render_token_transfers(
+    token_transfers: Vec<TokenTransfer>,
+    token_metadata: &HashMap<Address, TokenMetadata>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if token_transfers.is_empty() {
+        return Ok("".to_string());
+    }
+
+    let mut rows = String::new();
+
+    // Header row
+    let header_cells = [
+        "transaction hash",
+        "block number",
+        "timestamp",
+        "token",
+        "from",
+        "to",
+        "amount",
+    ];
+
+    let mut row = String::new();
+    for header_cell in header_cells {
+        row.push_str(&HEADER_CELL_HTML.replace("{data}", header_cell));
+    }
+    rows.push_str(&ROW_HTML.replace("{cells}", &row));
+
+    for token_transfer in token_transfers {
+        let cells = [
+            format!("{:?}", token_transfer.transaction_hash),
+            format!("{:?}", token_transfer.block_number),
+            date_time_from_timestamp_sec(token_transfer.timestamp).to_string(),
+            render_token(token_transfer.token, token_metadata),
+            format!("{:?}", token_transfer.from),
+            format!("{:?}", token_transfer.to),
+            escape_html(
+                &token_metadata
+                    .get(&token_transfer.token)
+                    .cloned()
+                    .unwrap_or_default()
+                    .format_amount(token_transfer.amount),
+            ),
+        ];
+
+        let mut row = String::new();
+        for cell in cells {
+            row.push_str(&DATA_CELL_HTML.replace("{data}", &cell));
+        }
+        rows.push_str(&ROW_HTML.replace("{cells}", &row));
+    }
+
+    let html_string = TOKEN_TRANSFERS_HTML.replace("{rows}", &rows);
+
+    Ok(html_string)
+}
+
+fn render_nft_transfers(
+    account: Address,
+    nft_transfers: Vec<NftTransfer>,
+    token_metadata: &HashMap<Address, TokenMetadata>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if nft_transfers.is_empty() {
+        return Ok("".to_string());
+    }
+
+    let mut rows = String::new();
+
+    // Header row
+    let header_cells = [
+        "transaction hash",
+        "block number",
+        "timestamp",
+        "standard",
+        "collection",
+        "token id",
+        "quantity",
+        "direction",
+        "from",
+        "to",
+    ];
+
+    let mut row = String::new();
+    for header_cell in header_cells {
+        row.push_str(&HEADER_CELL_HTML.replace("{data}", header_cell));
+    }
+    rows.push_str(&ROW_HTML.replace("{cells}", &row));
+
+    for nft_transfer in nft_transfers {
+        let direction = if nft_transfer.from == nft_transfer.to {
+            "self"
+        } else if nft_transfer.to == account {
+            "in"
+        } else {
+            "out"
+        };
+
+        let cells = [
+            format!("{:?}", nft_transfer.transaction_hash),
+            format!("{:?}", nft_transfer.block_number),
+            date_time_from_timestamp_sec(nft_transfer.timestamp).to_string(),
+            nft_transfer.standard,
+            render_token(nft_transfer.collection, token_metadata),
+            nft_transfer.token_id.to_string(),
+            nft_transfer.quantity.to_string(),
+            direction.to_string(),
+            format!("{:?}", nft_transfer.from),
+            format!("{:?}", nft_transfer.to),
+        ];
+
+        let mut row = String::new();
+        for cell in cells {
+            row.push_str(&DATA_CELL_HTML.replace("{data}", &cell));
+        }
+        rows.push_str(&ROW_HTML.replace("{cells}", &row));
+    }
+
+    let html_string = NFT_TRANSFERS_HTML.replace("{rows}", &rows);
+
+    Ok(html_string)
+}
+
+fn render_balance_series(
+    balance_series: Vec<BalancePoint>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if balance_series.is_empty() {
+        return Ok("".to_string());
+    }
+
+    let mut rows = String::new();
+
+    // Header row
+    let header_cells = ["date", "block number", "balance"];
+
+    let mut row = String::new();
+    for header_cell in header_cells {
+        row.push_str(&HEADER_CELL_HTML.replace("{data}", header_cell));
+    }
+    rows.push_str(&ROW_HTML.replace("{cells}", &row));
+
+    for balance_point in &balance_series {
+        let cells = [
+            balance_point.date.to_string(),
+            format!("{:?}", balance_point.block_number),
+            format!("{} ETH", balance_point.balance.as_u128() as f64 / 1e18),
+        ];
+
+        let mut row = String::new();
+        for cell in cells {
+            row.push_str(&DATA_CELL_HTML.replace("{data}", &cell));
+        }
+        rows.push_str(&ROW_HTML.replace("{cells}", &row));
+    }
+
+    let chart_points: Vec<(String, f64)> = balance_series
+        .iter()
+        .map(|v| (v.date.to_string(), v.balance.as_u128() as f64 / 1e18))
+        .collect();
+
+    let html_string = BALANCE_SERIES_HTML;
+    let html_string = html_string.replace("{chart}", &render_chart(&chart_points));
+    let html_string = html_string.replace("{rows}", &rows);
+
+    Ok(html_string)
+}
+
+/// Result: SVG line chart of `points`
+/// Param `points` - (x axis label, value), in the order of the x axis
+fn render_chart(points: &[(String, f64)]) -> String {
+    const WIDTH: f64 = 800.0;
+    const HEIGHT: f64 = 300.0;
+    const PADDING: f64 = 60.0;
+
+    let min = points.iter().map(|v| v.1).fold(f64::INFINITY, f64::min);
+    let max = points.iter().map(|v| v.1).fold(f64::NEG_INFINITY, f64::max);
+    // Flat line is drawn in the middle
+    let (min, max) = if max > min {
+        (min, max)
+    } else {
+        (min - 1.0, max + 1.0)
+    };
+
+    let x_step = (WIDTH - 2.0 * PADDING) / (points.len().max(2) - 1) as f64;
+    let coordinates: Vec<(f64, f64)> = points
+        .iter()
+        .enumerate()
+        .map(|(i, (_, value))| {
+            let x = PADDING + i as f64 * x_step;
+            let y = HEIGHT - PADDING - (value - min) / (max - min) * (HEIGHT - 2.0 * PADDING);
+
+            (x, y)
+        })
+        .collect();
+
+    let polyline_points: Vec<String> = coordinates
+        .iter()
+        .map(|(x, y)| format!("{:.1},{:.1}", x, y))
+        .collect();
+    let markers: String = coordinates
+        .iter()
+        .map(|(x, y)| {
+            format!(
+                "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"steelblue\"/>",
+                x, y
+            )
+        })
+        .collect();
+
+    let axes = format!(
+        "<line x1=\"{p}\" y1=\"{p}\" x2=\"{p}\" y2=\"{b}\" stroke=\"gray\"/><line x1=\"{p}\" y1=\"{b}\" x2=\"{r}\" y2=\"{b}\" stroke=\"gray\"/>",
+        p = PADDING,
+        b = HEIGHT - PADDING,
+        r = WIDTH - PADDING,
+    );
+
+    // Min and max values at the y axis, first and last labels at the x axis
+    let mut labels = format!(
+        "<text x=\"{x}\" y=\"{max_y}\" text-anchor=\"end\">{max}</text><text x=\"{x}\" y=\"{min_y}\" text-anchor=\"end\">{min}</text>",
+        x = PADDING - 5.0,
+        max_y = PADDING + 4.0,
+        min_y = HEIGHT - PADDING + 4.0,
+        max = max,
+        min = min,
+    );
+    if let (Some((first, _)), Some(((last, _), (last_x, _)))) =
+        (points.first(), points.last().zip(coordinates.last()))
+    {
+        labels.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"start\">{}</text>",
+            PADDING,
+            HEIGHT - PADDING + 20.0,
+            first,
+        ));
+        if points.len() > 1 {
+            labels.push_str(&format!(
+                "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>",
+                last_x,
+                HEIGHT - PADDING + 20.0,
+                last,
+            ));
+        }
+    }
+
+    CHART_SVG
+        .replace("{width}", &WIDTH.to_string())
+        .replace("{height}", &HEIGHT.to_string())
+        .replace("{axes}", &axes)
+        .replace("{points}", &polyline_points.join(" "))
+        .replace("{markers}", &markers)
+        .replace("{labels}", &labels)
+}
+
+/// Result: name and symbol of `token`, when they are known, followed by its address
+fn render_token(token: Address, token_metadata: &HashMap<Address, TokenMetadata>) -> String {
+    let metadata = token_metadata.get(&token);
+
+    let label = match metadata.map(|v| (&v.name, &v.symbol)) {
+        Some((Some(name), Some(symbol))) => format!("{} ({}) ", name, symbol),
+        Some((Some(label), None) | (None, Some(label))) => format!("{} ", label),
+        _ => "".to_string(),
+    };
+
+    format!("{}{:?}", escape_html(&label), token)
+}
+
+/// Token names and symbols are arbitrary strings set by contracts.
+/// Braces are escaped too, so that they aren't taken for template placeholders, e.g. `{rows}`
+fn escape_html(string: &str) -> String {
+    string
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('{', "&#123;")
+        .replace('}', "&#125;")
+}
+
 fn date_time_from_timestamp_sec(timestamp_sec: u64) -> DateTime<Utc> {
     let naive = NaiveDateTime::from_timestamp(timestamp_sec as i64, 0);
 
     DateTime::from_utc(naive, Utc)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_token_name_is_not_a_placeholder() {
+        let html_string = TOKEN_TRANSFERS_HTML.replace("{rows}", &escape_html("<b>{rows}</b>"));
+
+        assert!(!html_string.contains("{rows}"));
+        assert!(html_string.contains("&lt;b&gt;&#123;rows&#125;&lt;/b&gt;"));
+    }
+}
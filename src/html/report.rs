@@ -0,0 +1,25 @@
+use crate::balance::{BalancePoint, Portfolio};
+use crate::cached_transactions::TransactionWithReceipt;
+use crate::token_metadata::TokenMetadata;
+use crate::tokens::{NftTransfer, TokenTransfer};
+use crate::traces::InternalTransaction;
+use std::collections::HashMap;
+use web3::types::Address;
+
+/// Everything fetched for one account
+pub struct AccountReport {
+    pub account: Address,
+    pub transactions: Vec<TransactionWithReceipt>,
+    /// Empty when traces are off
+    pub internal_transactions: Vec<InternalTransaction>,
+    /// Empty when token transfers are off
+    pub token_transfers: Vec<TokenTransfer>,
+    /// Empty when NFT transfers are off
+    pub nft_transfers: Vec<NftTransfer>,
+    /// Key - token or NFT collection of the transfers or of the portfolio
+    pub token_metadata: HashMap<Address, TokenMetadata>,
+    /// `None` when there is no timestamp
+    pub portfolio: Option<Portfolio>,
+    /// Empty when there is no balance series
+    pub balance_series: Vec<BalancePoint>,
+}
@@ -1,5 +1,15 @@
-pub const RESULTS_HTML: &str = "<!DOCTYPE html><html><head><style>th, td {border: solid 1px; padding: 5px 10px; white-space: nowrap;}</style></head><body><h1>Results</h1><article><h2>Account</h2><div><span>{account}</span></div></article>{balance}<article><h2>Transactions</h2><table>{rows}</table></article></body></html>";
-pub const BALANCE_HTML: &str = "<article><h2>Balance</h2><div><span style=\"font-weight: bold;\">Timestamp: </span><span>{timestamp}</span></div><div><span style=\"font-weight: bold;\">Balance: </span><span>{balance}</span></div></article>";
+pub const RESULTS_HTML: &str = "<!DOCTYPE html><html><head><style>th, td {border: solid 1px; padding: 5px 10px; white-space: nowrap;}</style></head><body><h1>Results</h1>{accounts}</body></html>";
+pub const ACCOUNT_HTML: &str = "<section><article><h2>Account</h2><div><span>{account}</span></div></article>{portfolio}{balance_series}<article><h2>Transactions</h2><table>{rows}</table></article>{internal_transactions}{token_transfers}{nft_transfers}</section>";
+pub const INTERNAL_TRANSACTIONS_HTML: &str =
+    "<article><h2>Internal transactions</h2><table>{rows}</table></article>";
+pub const TOKEN_TRANSFERS_HTML: &str =
+    "<article><h2>Token transfers</h2><table>{rows}</table></article>";
+pub const NFT_TRANSFERS_HTML: &str =
+    "<article><h2>NFT transfers</h2><table>{rows}</table></article>";
+pub const PORTFOLIO_HTML: &str = "<article><h2>Holdings</h2><div><span style=\"font-weight: bold;\">Timestamp: </span><span>{timestamp}</span></div><div><span style=\"font-weight: bold;\">Block: </span><span>{block_number}</span></div><table>{rows}</table></article>";
+pub const BALANCE_SERIES_HTML: &str =
+    "<article><h2>Balance history</h2><div>{chart}</div><table>{rows}</table></article>";
+pub const CHART_SVG: &str = "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" style=\"font: 12px sans-serif;\">{axes}<polyline points=\"{points}\" fill=\"none\" stroke=\"steelblue\" stroke-width=\"2\"/>{markers}{labels}</svg>";
 pub const ROW_HTML: &str = "<tr>{cells}</tr>";
 pub const HEADER_CELL_HTML: &str = "<th>{data}</th>";
 pub const DATA_CELL_HTML: &str = "<td>{data}</td>";
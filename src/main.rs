@@ -1,18 +1,39 @@
-use crate::balance::get_balance_by_timestamp;
+use crate::balance::{
+    find_block_by_timestamp, get_balance_series, get_balances, get_token_balances, Portfolio,
+};
+use crate::block_timestamps::BlockTimestamps;
 use crate::cached_transactions::CachedTransactions;
 use crate::config::Config;
 use crate::connection::try_connect;
+use crate::follow::{watch_new_heads, FollowOutput};
 use crate::html::file::open_results_in_browser;
+use crate::html::report::AccountReport;
 use crate::logging::start_logger;
-use web3::types::U64;
+use crate::rpc::RetryPolicy;
+use crate::shutdown::watch_shutdown_signals;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use web3::types::{Address, U64};
 
 mod balance;
+mod block_intervals;
+mod block_store;
+mod block_timestamps;
 mod cached_transactions;
 mod config;
 mod connection;
 mod constants;
+mod follow;
 mod html;
 mod logging;
+mod progress;
+mod rpc;
+mod scanned_ranges;
+mod shutdown;
+mod token_metadata;
+mod tokens;
+mod traces;
+mod transports;
 
 #[macro_use]
 extern crate log;
@@ -24,38 +45,222 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = Config::new()?;
     debug!("Got config: {:?}", config);
 
-    let web3 = try_connect(config.jsonrpc_url)?;
+    let retry_policy = RetryPolicy::new(&config);
 
-    let account = config
-        .account
-        .parse()
-        .map_err(|e| format!("Account address parse error: {}", e))?;
+    let web3 = try_connect(
+        &config.jsonrpc_urls,
+        config.max_concurrency,
+        Duration::from_secs(config.rpc_timeout),
+        config.quorum,
+    )
+    .await?;
 
-    let balance = if let Some(timestamp) = config.timestamp {
-        info!("Fetch balance started.");
-        let balance = get_balance_by_timestamp(&web3, account, timestamp)
-            .await?
-            .map(|v| (timestamp, v));
+    let db = sled::open("db")?;
+    let chain_id = retry_policy
+        .call("eth_chainId", || web3.eth().chain_id())
+        .await?;
+    let block_timestamps = BlockTimestamps::new(&db, chain_id)?;
+
+    if let Some(datetime) = config.block_at {
+        let block_number =
+            find_block_by_timestamp(&web3, &retry_policy, &block_timestamps, datetime)
+                .await?
+                .ok_or_else(|| format!("No block at or after {}", datetime))?;
+        db.flush_async().await?;
+
+        println!("{}", block_number);
+
+        return Ok(());
+    }
+
+    let mut accounts: Vec<Address> = Vec::new();
+    for account in &config.accounts {
+        let account = account
+            .parse()
+            .map_err(|e| format!("Account address parse error: {}", e))?;
+
+        if !accounts.contains(&account) {
+            accounts.push(account);
+        }
+    }
+
+    let mut balance_tokens: Vec<Address> = Vec::new();
+    for token in &config.balance_tokens {
+        let token = token
+            .parse()
+            .map_err(|e| format!("Token address parse error: {}", e))?;
+
+        if !balance_tokens.contains(&token) {
+            balance_tokens.push(token);
+        }
+    }
+
+    // Balances of ETH and of tokens are taken at the same block
+    let balance_block = if let Some(timestamp) = config.timestamp {
+        find_block_by_timestamp(&web3, &retry_policy, &block_timestamps, timestamp).await?
+    } else {
+        None
+    };
+    let balances = if let Some(balance_block) = balance_block {
+        info!("Fetch balance started. Block: {}.", balance_block);
+        let balances = get_balances(&web3, &retry_policy, &accounts, balance_block).await?;
         info!("Fetch balance finished.");
 
-        balance
+        Some(balances)
     } else {
         None
     };
 
-    let client = CachedTransactions::new(web3).await?;
+    let balance_series = if let Some(series) = config.series {
+        info!("Fetch balance series started.");
+        let balance_series = get_balance_series(
+            &web3,
+            &retry_policy,
+            &block_timestamps,
+            &accounts,
+            series,
+            config.series_interval,
+            config.max_concurrency,
+        )
+        .await?;
+        info!("Fetch balance series finished.");
+
+        balance_series
+    } else {
+        Vec::new()
+    };
+    // In the same order as `accounts`
+    let mut balance_series = balance_series.into_iter();
+
+    // In follow mode the scan goes up to the head, and the next blocks are processed as they come
+    let heads = if config.follow {
+        Some(watch_new_heads(web3.clone(), &retry_policy, &config.jsonrpc_urls).await?)
+    } else {
+        None
+    };
+    let block_end = match &heads {
+        Some(heads) => Some(*heads.borrow()),
+        None => config.block_end.map(U64::from),
+    };
+
+    let client = CachedTransactions::new(
+        web3.clone(),
+        db,
+        &config,
+        retry_policy.clone(),
+        watch_shutdown_signals(),
+    )
+    .await?;
 
     info!("Fetch transactions started.");
     let transactions = client
-        .get_by_account(
-            account,
-            U64::from(config.block_start),
-            config.block_end.map(U64::from),
-        )
+        .get_by_accounts(&accounts, U64::from(config.block_start), block_end)
         .await?;
     info!("Fetch transactions finished.");
 
-    open_results_in_browser(config.account, transactions, balance)?;
+    // Without the token list - every ERC-20 token the account transferred up to the balance block
+    let transferred_tokens = match balance_block {
+        Some(balance_block) if balance_tokens.is_empty() => {
+            info!(
+                "Fetch transferred tokens started. Block end: {}.",
+                balance_block
+            );
+            let transferred_tokens = client
+                .get_transferred_tokens(&accounts, balance_block)
+                .await?;
+            info!("Fetch transferred tokens finished.");
+
+            transferred_tokens
+        }
+        _ => HashMap::new(),
+    };
+
+    let tokens: BTreeSet<Address> = transactions
+        .iter()
+        .flat_map(|(_, transactions)| {
+            let tokens = transactions.token_transfers.iter().map(|v| v.token);
+            let collections = transactions.nft_transfers.iter().map(|v| v.collection);
+
+            tokens.chain(collections)
+        })
+        .chain(balance_tokens.iter().cloned())
+        .chain(transferred_tokens.values().flatten().cloned())
+        .collect();
+    let token_metadata = client.get_token_metadata(&tokens).await?;
+
+    let mut reports = Vec::with_capacity(transactions.len());
+    for (i, (account, transactions)) in transactions.into_iter().enumerate() {
+        let portfolio = match (config.timestamp, balance_block, &balances) {
+            (Some(timestamp), Some(balance_block), Some(balances)) => {
+                let tokens: Vec<Address> = if balance_tokens.is_empty() {
+                    transferred_tokens
+                        .get(&account)
+                        .into_iter()
+                        .flatten()
+                        .cloned()
+                        .collect()
+                } else {
+                    balance_tokens.clone()
+                };
+
+                let token_balances =
+                    get_token_balances(&web3, &retry_policy, account, &tokens, balance_block)
+                        .await?;
+
+                Some(Portfolio {
+                    timestamp,
+                    block_number: balance_block,
+                    balance: balances[i],
+                    token_balances,
+                })
+            }
+            _ => None,
+        };
+
+        reports.push(AccountReport {
+            account,
+            // Metadata of the tokens of this account only
+            token_metadata: token_metadata
+                .iter()
+                .filter(|(token, _)| {
+                    transactions
+                        .token_transfers
+                        .iter()
+                        .any(|v| v.token == **token)
+                        || transactions
+                            .nft_transfers
+                            .iter()
+                            .any(|v| v.collection == **token)
+                        || balance_tokens.contains(token)
+                        || transferred_tokens
+                            .get(&account)
+                            .is_some_and(|tokens| tokens.contains(token))
+                })
+                .map(|(token, metadata)| (*token, metadata.clone()))
+                .collect(),
+            transactions: transactions.transactions,
+            internal_transactions: transactions.internal_transactions,
+            token_transfers: transactions.token_transfers,
+            nft_transfers: transactions.nft_transfers,
+            portfolio,
+            balance_series: balance_series.next().unwrap_or_default(),
+        });
+    }
+
+    open_results_in_browser(reports)?;
+
+    if let (Some(heads), Some(block_end)) = (heads, block_end) {
+        let mut output = FollowOutput::new(config.follow_output.as_deref())?;
+
+        client
+            .follow(
+                &accounts,
+                (block_end + 1).max(U64::from(config.block_start)),
+                heads,
+                |account, transaction| output.write(account, transaction),
+            )
+            .await?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,146 @@
+use std::cell::Cell;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+/// How often the live bar is redrawn
+const BAR_RENDER_INTERVAL: Duration = Duration::from_millis(200);
+/// How often progress is logged when stderr is not a terminal
+const LOG_RENDER_INTERVAL: Duration = Duration::from_secs(10);
+const BAR_WIDTH: u64 = 30;
+
+/// Progress of a block scan.
+/// Rendered as a live bar when stderr is a terminal and as periodic log lines otherwise
+pub struct Progress {
+    is_terminal: bool,
+
+    total_blocks: Cell<u64>,
+    scanned_blocks: Cell<u64>,
+    transactions: Cell<u64>,
+    requests: Cell<u64>,
+
+    started_at: Cell<Instant>,
+    rendered_at: Cell<Instant>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            is_terminal: io::stderr().is_terminal(),
+            total_blocks: Cell::new(0),
+            scanned_blocks: Cell::new(0),
+            transactions: Cell::new(0),
+            requests: Cell::new(0),
+            started_at: Cell::new(Instant::now()),
+            rendered_at: Cell::new(Instant::now()),
+        }
+    }
+
+    pub fn start(&self, total_blocks: u64) {
+        self.total_blocks.set(total_blocks);
+        self.scanned_blocks.set(0);
+        self.transactions.set(0);
+        self.requests.set(0);
+        self.started_at.set(Instant::now());
+        self.rendered_at.set(Instant::now());
+    }
+
+    pub fn add_request(&self) {
+        self.requests.set(self.requests.get() + 1);
+    }
+
+    /// Param `transactions` - number of matching transactions in the block
+    pub fn add_block(&self, transactions: usize) {
+        self.scanned_blocks.set(self.scanned_blocks.get() + 1);
+        self.transactions
+            .set(self.transactions.get() + transactions as u64);
+
+        let render_interval = if self.is_terminal {
+            BAR_RENDER_INTERVAL
+        } else {
+            LOG_RENDER_INTERVAL
+        };
+
+        // Not started progress (e.g. of blocks processed in follow mode) isn't rendered
+        if self.total_blocks.get() > 0 && self.rendered_at.get().elapsed() >= render_interval {
+            self.render();
+        }
+    }
+
+    pub fn finish(&self) {
+        if self.total_blocks.get() == 0 {
+            return;
+        }
+
+        self.render();
+
+        if self.is_terminal {
+            eprintln!();
+        }
+
+        self.total_blocks.set(0);
+    }
+
+    fn render(&self) {
+        self.rendered_at.set(Instant::now());
+
+        let total_blocks = self.total_blocks.get();
+        let scanned_blocks = self.scanned_blocks.get();
+        let elapsed = self.started_at.get().elapsed().as_secs_f64();
+
+        let requests_per_sec = if elapsed > 0.0 {
+            self.requests.get() as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        // Remaining blocks are expected to be scanned at the average speed
+        let eta = if scanned_blocks > 0 {
+            let remaining_blocks = total_blocks.saturating_sub(scanned_blocks);
+
+            Some(Duration::from_secs_f64(
+                elapsed * remaining_blocks as f64 / scanned_blocks as f64,
+            ))
+        } else {
+            None
+        };
+
+        if self.is_terminal {
+            let filled = (BAR_WIDTH * scanned_blocks / total_blocks.max(1)).min(BAR_WIDTH);
+            let bar = "=".repeat(filled as usize) + &" ".repeat((BAR_WIDTH - filled) as usize);
+
+            let mut stderr = io::stderr();
+            // `\x1b[K` - clear the rest of the line
+            let _ = write!(
+                stderr,
+                "\r[{}] {}/{} blocks | {} transactions | {:.1} req/s | ETA {}\x1b[K",
+                bar,
+                scanned_blocks,
+                total_blocks,
+                self.transactions.get(),
+                requests_per_sec,
+                Self::format_duration(eta),
+            );
+            let _ = stderr.flush();
+        } else {
+            info!(
+                "Progress: blocks_scanned={} blocks_total={} transactions={} requests_per_sec={:.1} eta_sec={}",
+                scanned_blocks,
+                total_blocks,
+                self.transactions.get(),
+                requests_per_sec,
+                eta.map(|v| v.as_secs().to_string()).unwrap_or_default(),
+            );
+        }
+    }
+
+    /// Result: `HH:MM:SS`
+    fn format_duration(duration: Option<Duration>) -> String {
+        if let Some(duration) = duration {
+            let secs = duration.as_secs();
+
+            format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        } else {
+            "--:--:--".to_string()
+        }
+    }
+}
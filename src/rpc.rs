@@ -0,0 +1,145 @@
+use crate::config::Config;
+use jsonrpc_core::ErrorCode;
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use web3::error::TransportError;
+
+/// Delay before the first retry. Every next delay is twice longer
+const BACKOFF_MIN: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Retries of failed JSON RPC calls, shared by all calls of the crawl
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Max number of retries of one call
+    retries: u32,
+    /// Calls fail after this moment. `None` means no deadline
+    deadline: Option<Instant>,
+}
+
+impl RetryPolicy {
+    /// Crawl deadline is counted from now
+    pub fn new(config: &Config) -> Self {
+        Self {
+            retries: config.rpc_retries,
+            deadline: config
+                .crawl_deadline
+                .map(|v| Instant::now() + Duration::from_secs(v)),
+        }
+    }
+
+    /// Repeats `request` with exponential backoff while it fails with a temporary error
+    /// (network error, timeout, rate limit, HTTP 5xx)
+    /// Param `description` - call and its block, used in logs and in the final error
+    pub async fn call<T, F, Fut>(
+        &self,
+        description: &str,
+        request: F,
+    ) -> Result<T, Box<dyn std::error::Error>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = web3::Result<T>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let result = match self.deadline {
+                Some(deadline) => time::timeout_at(deadline, request())
+                    .await
+                    .map_err(|_| format!("{} failed: crawl deadline exceeded", description))?,
+                None => request().await,
+            };
+
+            let e = match result {
+                Ok(result) => return Ok(result),
+                Err(e) => e,
+            };
+
+            if attempt >= self.retries || !Self::is_temporary(&e) {
+                Err(format!(
+                    "{} failed after {} attempt(s): {}",
+                    description,
+                    attempt + 1,
+                    e,
+                ))?;
+            }
+
+            let delay = Self::backoff(attempt);
+            if self.deadline.is_some_and(|v| Instant::now() + delay >= v) {
+                Err(format!(
+                    "{} failed: crawl deadline exceeded. Last error: {}",
+                    description, e,
+                ))?;
+            }
+
+            attempt += 1;
+            warn!(
+                "{} failed: {}. Retry {}/{} in {:?}.",
+                description, e, attempt, self.retries, delay,
+            );
+
+            time::sleep(delay).await;
+        }
+    }
+
+    /// Result: random delay (jitter) between half and full of the exponential backoff
+    fn backoff(attempt: u32) -> Duration {
+        let backoff = BACKOFF_MIN
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(BACKOFF_MAX);
+
+        rand::thread_rng().gen_range(backoff / 2..=backoff)
+    }
+
+    /// Temporary error of a call: network error, timeout, rate limit, HTTP 5xx
+    pub fn is_temporary(e: &web3::Error) -> bool {
+        match e {
+            web3::Error::Unreachable | web3::Error::InvalidResponse(_) | web3::Error::Io(_) => true,
+            // Connection errors and timeouts
+            web3::Error::Transport(TransportError::Message(_)) => true,
+            web3::Error::Transport(TransportError::Code(code)) => {
+                *code == 408 || *code == 429 || *code >= 500
+            }
+            // Rate limits reported inside of JSON RPC response
+            web3::Error::Rpc(_) if Self::is_too_many_logs(e) => false,
+            web3::Error::Rpc(e) => {
+                let message = e.message.to_lowercase();
+
+                e.code == ErrorCode::ServerError(-32005)
+                    || message.contains("rate limit")
+                    || message.contains("too many requests")
+            }
+            _ => false,
+        }
+    }
+
+    /// Result: whether `e` means that the server doesn't implement the method,
+    /// e.g. `eth_getBlockReceipts` or `trace_block`
+    pub fn is_method_not_found(e: &web3::Error) -> bool {
+        matches!(e, web3::Error::Rpc(e) if e.code == ErrorCode::MethodNotFound)
+    }
+
+    /// `eth_getLogs` query refused because of its size. Providers limit it by the number
+    /// of blocks, logs or response size, and word their errors differently.
+    /// Some of them use the rate limit code `-32005` for it
+    pub fn is_too_many_logs(e: &web3::Error) -> bool {
+        let message = match e {
+            web3::Error::Rpc(e) => e.message.to_lowercase(),
+            _ => return false,
+        };
+
+        [
+            "query returned more than",
+            "too many results",
+            "too many logs",
+            "block range",
+            "range is too",
+            "response size",
+            "size exceeded",
+        ]
+        .iter()
+        .any(|v| message.contains(v))
+    }
+}
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use web3::types::{Address, U256, U64};
+
+/// Block range that was fully scanned for account transactions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScannedRange {
+    pub chain_id: U256,
+    pub account: Address,
+    pub block_start: U64,
+    pub block_end: U64,
+    /// Whether internal transactions were searched with traces
+    #[serde(default)]
+    pub traces: bool,
+    /// Whether NFT transfers were searched together with token transfers
+    #[serde(default)]
+    pub nft_transfers: bool,
+    /// Whether contract creations of the account were searched
+    #[serde(default)]
+    pub creations: bool,
+    /// Unix timestamp (seconds) of the moment the range was saved
+    pub scanned_at: i64,
+}
+
+impl ScannedRange {
+    pub fn key(&self) -> String {
+        // Add leading zeros to `block_number` string view
+        format!(
+            "{}{:?}_{:0>32?}_{:0>32?}",
+            Self::stringify_key_prefix(self.chain_id),
+            self.account,
+            self.block_start,
+            self.block_end,
+        )
+    }
+
+    /// Prefix of keys of all ranges scanned on chain `chain_id`
+    pub fn stringify_key_prefix(chain_id: U256) -> String {
+        format!("{}_", chain_id)
+    }
+}
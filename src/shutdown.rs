@@ -0,0 +1,47 @@
+use std::io;
+use tokio::sync::watch;
+
+/// Result: receiver which gets `true` on the first SIGINT (Ctrl-C) or SIGTERM.
+/// The second signal terminates the program immediately
+pub fn watch_shutdown_signals() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+
+    tokio::spawn(async move {
+        if let Err(e) = wait_signal().await {
+            error!("Shutdown signal handler error: {}", e);
+
+            return;
+        }
+
+        warn!("Shutdown signal received. Saving progress... Send it again to exit immediately.");
+        if sender.send(true).is_err() {
+            // Nobody listens - nothing to save
+            std::process::exit(130);
+        }
+
+        if wait_signal().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    receiver
+}
+
+#[cfg(unix)]
+async fn wait_signal() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r?,
+        _ = sigterm.recv() => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_signal() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use web3::ethabi::{self, ParamType, Token};
+use web3::types::{Address, Bytes, U256};
+
+/// Selector of `symbol()`
+pub const SYMBOL_SELECTOR: [u8; 4] = [0x95, 0xd8, 0x9b, 0x41];
+/// Selector of `name()`
+pub const NAME_SELECTOR: [u8; 4] = [0x06, 0xfd, 0xde, 0x03];
+/// Selector of `decimals()`
+pub const DECIMALS_SELECTOR: [u8; 4] = [0x31, 0x3c, 0xe5, 0x67];
+
+/// Greatest number of decimals whose power of ten fits into `U256`
+const MAX_DECIMALS: u8 = 77;
+
+/// Optional ERC-20 (and ERC-721) metadata of a token contract.
+/// `None` - contract doesn't implement the function or returned garbage
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TokenMetadata {
+    pub symbol: Option<String>,
+    pub name: Option<String>,
+    pub decimals: Option<u8>,
+}
+
+impl TokenMetadata {
+    /// Result: `amount` in whole tokens with the symbol, e.g. `1.5 USDC`.
+    /// Without decimals - in the smallest units of the token
+    pub fn format_amount(&self, amount: U256) -> String {
+        let amount = match self.decimals {
+            Some(decimals) => format_decimal(amount, decimals),
+            None => amount.to_string(),
+        };
+
+        match &self.symbol {
+            Some(symbol) => format!("{} {}", amount, symbol),
+            None => amount,
+        }
+    }
+}
+
+/// DB of metadata of token contracts. Metadata is fetched once and never changes
+pub struct TokenMetadataStore {
+    /// Key - (chain_id, token)
+    /// Value - `TokenMetadata`
+    token_metadata: sled::Tree,
+}
+
+impl TokenMetadataStore {
+    pub fn new(db: &sled::Db) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            token_metadata: db.open_tree("token_metadata")?,
+        })
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    pub fn get(
+        &self,
+        chain_id: U256,
+        token: Address,
+    ) -> Result<Option<TokenMetadata>, Box<dyn std::error::Error>> {
+        let token_metadata = self
+            .token_metadata
+            .get(Self::stringify_key(chain_id, token))?
+            .map(|ivec| serde_json::from_slice(&ivec))
+            .transpose()?;
+
+        Ok(token_metadata)
+    }
+
+    /// TODO: Remove json layer (serialize directly into bytes)
+    pub fn save(
+        &self,
+        chain_id: U256,
+        token: Address,
+        token_metadata: &TokenMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // TODO: Remove json layer (serialize directly into bytes)
+        let bytes = serde_json::to_vec(token_metadata)?;
+
+        self.token_metadata
+            .insert(Self::stringify_key(chain_id, token), bytes)?;
+
+        Ok(())
+    }
+
+    fn stringify_key(chain_id: U256, token: Address) -> String {
+        format!("{}_{:?}", chain_id, token)
+    }
+}
+
+/// Result: string returned by `symbol()` or `name()`.
+/// Legacy tokens (e.g. MKR) return `bytes32` instead of `string`
+pub fn decode_string(output: &Bytes) -> Option<String> {
+    let string = if output.0.len() == 32 {
+        String::from_utf8(output.0.clone()).ok()?
+    } else {
+        match ethabi::decode(&[ParamType::String], &output.0)
+            .ok()?
+            .pop()?
+        {
+            Token::String(string) => string,
+            _ => return None,
+        }
+    };
+
+    // `bytes32` strings are padded with zeros
+    let string = string.trim_matches(char::from(0)).trim();
+
+    if string.is_empty() || string.chars().any(char::is_control) {
+        return None;
+    }
+
+    Some(string.to_string())
+}
+
+/// Result: number returned by `decimals()`
+pub fn decode_decimals(output: &Bytes) -> Option<u8> {
+    if output.0.len() != 32 {
+        return None;
+    }
+
+    let decimals = U256::from_big_endian(&output.0);
+    if decimals > U256::from(MAX_DECIMALS) {
+        return None;
+    }
+
+    Some(decimals.as_u32() as u8)
+}
+
+/// Result: `amount` divided by 10 ^ `decimals`, without trailing zeros of the fraction
+fn format_decimal(amount: U256, decimals: u8) -> String {
+    let divisor = U256::exp10(decimals as usize);
+    let integer = amount / divisor;
+    let fraction = amount % divisor;
+
+    if fraction.is_zero() {
+        return integer.to_string();
+    }
+
+    let fraction = format!(
+        "{:0>width$}",
+        fraction.to_string(),
+        width = decimals as usize
+    );
+
+    format!("{}.{}", integer, fraction.trim_end_matches('0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_decimal_with_fraction() {
+        assert_eq!(format_decimal(U256::from(1_500_000), 6), "1.5");
+        assert_eq!(format_decimal(U256::from(1_234_567), 6), "1.234567");
+    }
+
+    #[test]
+    fn format_decimal_without_fraction() {
+        assert_eq!(format_decimal(U256::from(2_000_000), 6), "2");
+        assert_eq!(format_decimal(U256::zero(), 18), "0");
+        assert_eq!(format_decimal(U256::from(42), 0), "42");
+    }
+
+    #[test]
+    fn format_decimal_less_than_one() {
+        assert_eq!(format_decimal(U256::from(5), 6), "0.000005");
+        assert_eq!(format_decimal(U256::exp10(17), 18), "0.1");
+    }
+
+    #[test]
+    fn format_decimal_of_max_amount() {
+        assert_eq!(
+            format_decimal(U256::MAX, MAX_DECIMALS),
+            "1.15792089237316195423570985008687907853269984665640564039457584007913129639935",
+        );
+    }
+
+    #[test]
+    fn format_amount_with_symbol() {
+        let metadata = TokenMetadata {
+            symbol: Some("USDC".to_string()),
+            name: None,
+            decimals: Some(6),
+        };
+
+        assert_eq!(metadata.format_amount(U256::from(1_500_000)), "1.5 USDC");
+        assert_eq!(
+            TokenMetadata::default().format_amount(U256::from(1_500_000)),
+            "1500000"
+        );
+    }
+
+    #[test]
+    fn decode_abi_string() {
+        let output = Bytes(ethabi::encode(&[Token::String("USD Coin".to_string())]));
+
+        assert_eq!(decode_string(&output).as_deref(), Some("USD Coin"));
+    }
+
+    #[test]
+    fn decode_bytes32_string() {
+        let mut output = b"MKR".to_vec();
+        output.resize(32, 0);
+
+        assert_eq!(decode_string(&Bytes(output)).as_deref(), Some("MKR"));
+    }
+
+    #[test]
+    fn decode_garbage_string() {
+        assert_eq!(decode_string(&Bytes(Vec::new())), None);
+        assert_eq!(decode_string(&Bytes(vec![0; 32])), None);
+    }
+
+    #[test]
+    fn decode_decimals_in_range() {
+        let mut output = vec![0; 32];
+        output[31] = 18;
+        assert_eq!(decode_decimals(&Bytes(output)), Some(18));
+
+        let mut output = vec![0; 32];
+        output[31] = MAX_DECIMALS + 1;
+        assert_eq!(decode_decimals(&Bytes(output)), None);
+    }
+}
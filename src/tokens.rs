@@ -0,0 +1,341 @@
+use serde::{Deserialize, Serialize};
+use web3::ethabi::{self, ParamType, Token};
+use web3::types::{Address, Log, H256, U256, U64};
+
+/// keccak256("Transfer(address,address,uint256)"). Same for ERC-20 and ERC-721
+pub const TRANSFER_TOPIC: &str =
+    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+/// keccak256("TransferSingle(address,address,address,uint256,uint256)")
+pub const TRANSFER_SINGLE_TOPIC: &str =
+    "0xc3d58168c5ae7397731d063d5bbf3d657854427343f4c083240f7aacaa2d0f62";
+/// keccak256("TransferBatch(address,address,address,uint256[],uint256[])")
+pub const TRANSFER_BATCH_TOPIC: &str =
+    "0x4a39dc06d4c0dbc64b70af90fd698a233a518aa5d07e595d983b8c0526c8f7fb";
+
+/// Transfer of ERC-20 tokens
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TokenTransfer {
+    pub block_number: U64,
+    /// Block timestamp
+    pub timestamp: u64,
+    pub transaction_hash: H256,
+    pub log_index: U256,
+    /// Token contract
+    pub token: Address,
+    pub from: Address,
+    pub to: Address,
+    /// In the smallest units of the token
+    pub amount: U256,
+}
+
+/// Transfer of ERC-721 or ERC-1155 tokens
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NftTransfer {
+    pub block_number: U64,
+    /// Block timestamp
+    pub timestamp: u64,
+    pub transaction_hash: H256,
+    pub log_index: U256,
+    /// Index of the token in `TransferBatch` event, `0` for other events
+    pub batch_index: usize,
+    /// `ERC-721` or `ERC-1155`
+    pub standard: String,
+    /// Token contract
+    pub collection: Address,
+    pub from: Address,
+    pub to: Address,
+    pub token_id: U256,
+    /// Always `1` for ERC-721
+    pub quantity: U256,
+}
+
+/// Result: transfer of `Transfer` event `log`.
+/// `None` - log is not an ERC-20 transfer (e.g. ERC-721 transfer with indexed token id)
+/// Param `timestamp` - timestamp of the log block
+pub fn from_transfer_log(log: &Log, timestamp: u64) -> Option<TokenTransfer> {
+    if log.removed == Some(true) || log.topics.len() != 3 || log.data.0.len() != 32 {
+        return None;
+    }
+
+    Some(TokenTransfer {
+        block_number: log.block_number?,
+        timestamp,
+        transaction_hash: log.transaction_hash?,
+        log_index: log.log_index?,
+        token: log.address,
+        from: address_from_topic(&log.topics[1]),
+        to: address_from_topic(&log.topics[2]),
+        amount: U256::from_big_endian(&log.data.0),
+    })
+}
+
+/// Result: transfers of ERC-721 `Transfer` or ERC-1155 `TransferSingle`/`TransferBatch`
+/// event `log`. Empty - log is not an NFT transfer.
+/// ERC-721 transfers of old contracts with not indexed token id look like ERC-20 ones,
+/// so they aren't found
+/// Param `timestamp` - timestamp of the log block
+pub fn from_nft_log(log: &Log, timestamp: u64) -> Vec<NftTransfer> {
+    if log.removed == Some(true) {
+        return Vec::new();
+    }
+
+    let (block_number, transaction_hash, log_index) =
+        match (log.block_number, log.transaction_hash, log.log_index) {
+            (Some(block_number), Some(tr_hash), Some(log_index)) => {
+                (block_number, tr_hash, log_index)
+            }
+            _ => return Vec::new(),
+        };
+
+    let nft_transfer = |batch_index, standard: &str, from, to, token_id, quantity| NftTransfer {
+        block_number,
+        timestamp,
+        transaction_hash,
+        log_index,
+        batch_index,
+        standard: standard.to_string(),
+        collection: log.address,
+        from,
+        to,
+        token_id,
+        quantity,
+    };
+
+    let topic = match log.topics.first() {
+        Some(topic) => format!("{:?}", topic),
+        None => return Vec::new(),
+    };
+
+    match (topic.as_str(), log.topics.len()) {
+        (TRANSFER_TOPIC, 4) if log.data.0.is_empty() => vec![nft_transfer(
+            0,
+            "ERC-721",
+            address_from_topic(&log.topics[1]),
+            address_from_topic(&log.topics[2]),
+            U256::from_big_endian(log.topics[3].as_bytes()),
+            U256::one(),
+        )],
+        // Topic 1 is the operator, who isn't necessarily the owner
+        (TRANSFER_SINGLE_TOPIC, 4) if log.data.0.len() == 64 => vec![nft_transfer(
+            0,
+            "ERC-1155",
+            address_from_topic(&log.topics[2]),
+            address_from_topic(&log.topics[3]),
+            U256::from_big_endian(&log.data.0[..32]),
+            U256::from_big_endian(&log.data.0[32..]),
+        )],
+        (TRANSFER_BATCH_TOPIC, 4) => {
+            let uint_array = ParamType::Array(Box::new(ParamType::Uint(256)));
+            let (token_ids, quantities) =
+                match ethabi::decode(&[uint_array.clone(), uint_array], &log.data.0).as_deref() {
+                    Ok([Token::Array(token_ids), Token::Array(quantities)])
+                        if token_ids.len() == quantities.len() =>
+                    {
+                        (token_ids.clone(), quantities.clone())
+                    }
+                    _ => return Vec::new(),
+                };
+
+            token_ids
+                .into_iter()
+                .zip(quantities)
+                .enumerate()
+                .filter_map(|(batch_index, (token_id, quantity))| {
+                    Some(nft_transfer(
+                        batch_index,
+                        "ERC-1155",
+                        address_from_topic(&log.topics[2]),
+                        address_from_topic(&log.topics[3]),
+                        token_id.into_uint()?,
+                        quantity.into_uint()?,
+                    ))
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Result: topic of indexed `address` event param
+pub fn address_to_topic(address: Address) -> H256 {
+    // Address is right-aligned in 32 bytes
+    H256::from(address)
+}
+
+fn address_from_topic(topic: &H256) -> Address {
+    Address::from_slice(&topic.as_bytes()[12..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use web3::types::Bytes;
+
+    const TOKEN: &str = "0xa1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1";
+    const FROM: &str = "0x1111111111111111111111111111111111111111";
+    const TO: &str = "0x2222222222222222222222222222222222222222";
+
+    fn topic(address: &str) -> H256 {
+        address_to_topic(address.parse().unwrap())
+    }
+
+    fn uint(value: u64) -> H256 {
+        H256::from_low_u64_be(value)
+    }
+
+    fn log(topics: Vec<H256>, data: Vec<u8>) -> Log {
+        serde_json::from_value(json!({
+            "address": TOKEN,
+            "topics": topics,
+            "data": Bytes::from(data),
+            "blockHash": H256::zero(),
+            "blockNumber": "0x64",
+            "transactionHash": H256::repeat_byte(0x33),
+            "transactionIndex": "0x0",
+            "logIndex": "0x5",
+            "removed": false,
+        }))
+        .unwrap()
+    }
+
+    fn transfer_topic() -> H256 {
+        TRANSFER_TOPIC.parse().unwrap()
+    }
+
+    #[test]
+    fn erc20_transfer() {
+        let log = log(
+            vec![transfer_topic(), topic(FROM), topic(TO)],
+            uint(1500).as_bytes().to_vec(),
+        );
+
+        let transfer = from_transfer_log(&log, 1_600_000_000).unwrap();
+
+        assert_eq!(transfer.block_number, U64::from(100));
+        assert_eq!(transfer.timestamp, 1_600_000_000);
+        assert_eq!(transfer.log_index, U256::from(5));
+        assert_eq!(transfer.token, TOKEN.parse().unwrap());
+        assert_eq!(transfer.from, FROM.parse().unwrap());
+        assert_eq!(transfer.to, TO.parse().unwrap());
+        assert_eq!(transfer.amount, U256::from(1500));
+    }
+
+    #[test]
+    fn erc721_transfer_is_not_erc20() {
+        let log = log(
+            vec![transfer_topic(), topic(FROM), topic(TO), uint(7)],
+            Vec::new(),
+        );
+
+        assert!(from_transfer_log(&log, 0).is_none());
+    }
+
+    #[test]
+    fn removed_log_is_skipped() {
+        let mut log = log(
+            vec![transfer_topic(), topic(FROM), topic(TO)],
+            uint(1).as_bytes().to_vec(),
+        );
+        log.removed = Some(true);
+
+        assert!(from_transfer_log(&log, 0).is_none());
+        assert!(from_nft_log(&log, 0).is_empty());
+    }
+
+    #[test]
+    fn erc721_transfer() {
+        let log = log(
+            vec![transfer_topic(), topic(FROM), topic(TO), uint(7)],
+            Vec::new(),
+        );
+
+        let transfers = from_nft_log(&log, 0);
+
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].standard, "ERC-721");
+        assert_eq!(transfers[0].from, FROM.parse().unwrap());
+        assert_eq!(transfers[0].to, TO.parse().unwrap());
+        assert_eq!(transfers[0].token_id, U256::from(7));
+        assert_eq!(transfers[0].quantity, U256::one());
+    }
+
+    #[test]
+    fn erc20_transfer_is_not_nft() {
+        let log = log(
+            vec![transfer_topic(), topic(FROM), topic(TO)],
+            uint(1).as_bytes().to_vec(),
+        );
+
+        assert!(from_nft_log(&log, 0).is_empty());
+    }
+
+    #[test]
+    fn erc1155_transfer_single() {
+        let operator = "0x3333333333333333333333333333333333333333";
+        let mut data = uint(9).as_bytes().to_vec();
+        data.extend_from_slice(uint(4).as_bytes());
+        let log = log(
+            vec![
+                TRANSFER_SINGLE_TOPIC.parse().unwrap(),
+                topic(operator),
+                topic(FROM),
+                topic(TO),
+            ],
+            data,
+        );
+
+        let transfers = from_nft_log(&log, 0);
+
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].standard, "ERC-1155");
+        assert_eq!(transfers[0].from, FROM.parse().unwrap());
+        assert_eq!(transfers[0].to, TO.parse().unwrap());
+        assert_eq!(transfers[0].token_id, U256::from(9));
+        assert_eq!(transfers[0].quantity, U256::from(4));
+    }
+
+    #[test]
+    fn erc1155_transfer_batch() {
+        let data = ethabi::encode(&[
+            Token::Array(vec![Token::Uint(1.into()), Token::Uint(2.into())]),
+            Token::Array(vec![Token::Uint(10.into()), Token::Uint(20.into())]),
+        ]);
+        let log = log(
+            vec![
+                TRANSFER_BATCH_TOPIC.parse().unwrap(),
+                topic(FROM),
+                topic(FROM),
+                topic(TO),
+            ],
+            data,
+        );
+
+        let transfers = from_nft_log(&log, 0);
+
+        let transfers: Vec<_> = transfers
+            .iter()
+            .map(|v| (v.batch_index, v.token_id.as_u64(), v.quantity.as_u64()))
+            .collect();
+        assert_eq!(transfers, vec![(0, 1, 10), (1, 2, 20)]);
+    }
+
+    #[test]
+    fn erc1155_transfer_batch_of_different_lengths() {
+        let data = ethabi::encode(&[
+            Token::Array(vec![Token::Uint(1.into()), Token::Uint(2.into())]),
+            Token::Array(vec![Token::Uint(10.into())]),
+        ]);
+        let log = log(
+            vec![
+                TRANSFER_BATCH_TOPIC.parse().unwrap(),
+                topic(FROM),
+                topic(FROM),
+                topic(TO),
+            ],
+            data,
+        );
+
+        assert!(from_nft_log(&log, 0).is_empty());
+    }
+}
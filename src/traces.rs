@@ -0,0 +1,531 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use web3::types::{Address, Block, Transaction, H256, U256, U64};
+
+/// ETH transfer made by a contract call inside of a transaction
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InternalTransaction {
+    pub block_number: U64,
+    /// Block timestamp
+    pub timestamp: u64,
+    pub transaction_hash: H256,
+    pub transaction_index: u64,
+    /// Indexes of the calls from the transaction call down to this one, e.g. `[0, 2]`
+    pub call_path: Vec<usize>,
+    /// `call`, `create`, `create2` or `selfdestruct`
+    pub call_type: String,
+    pub from: Address,
+    pub to: Address,
+    pub value: U256,
+}
+
+/// Trace of `trace_block` (Parity-style)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ParityTrace {
+    action: ParityAction,
+    result: Option<ParityResult>,
+    error: Option<String>,
+    trace_address: Vec<usize>,
+    transaction_hash: Option<H256>,
+    transaction_position: Option<u64>,
+    #[serde(rename = "type")]
+    trace_type: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ParityAction {
+    from: Option<Address>,
+    to: Option<Address>,
+    value: Option<U256>,
+    call_type: Option<String>,
+    /// Self destructed contract
+    address: Option<Address>,
+    refund_address: Option<Address>,
+    balance: Option<U256>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ParityResult {
+    /// Created contract
+    address: Option<Address>,
+}
+
+/// Trace of one transaction of `debug_traceBlockByNumber` with `callTracer` (Geth-style)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GethTransactionTrace {
+    /// Missing in responses of old Geth versions
+    tx_hash: Option<H256>,
+    /// Missing if tracing of the transaction failed, e.g. because of tracer timeout
+    result: Option<GethCallFrame>,
+    /// Tracing error
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GethCallFrame {
+    #[serde(rename = "type")]
+    call_type: String,
+    from: Address,
+    to: Option<Address>,
+    value: Option<U256>,
+    error: Option<String>,
+    #[serde(default)]
+    calls: Vec<GethCallFrame>,
+}
+
+/// Result: internal transactions of `trace_block` response `traces` of `block`,
+/// sorted by transaction index and call path.
+/// Calls of reverted transactions and subcalls of reverted calls are skipped
+pub fn from_parity_traces(
+    block: &Block<Transaction>,
+    traces: Value,
+) -> Result<Vec<InternalTransaction>, Box<dyn std::error::Error>> {
+    let traces: Vec<ParityTrace> = serde_json::from_value(traces)?;
+
+    // Calls with errors, changes made by them and by their subcalls are reverted
+    let reverted: Vec<(Option<H256>, &[usize])> = traces
+        .iter()
+        .filter(|trace| trace.error.is_some())
+        .map(|trace| (trace.transaction_hash, trace.trace_address.as_slice()))
+        .collect();
+
+    let mut internal_transactions = Vec::new();
+
+    for trace in &traces {
+        // Transaction call itself is the transaction, it isn't internal
+        if trace.trace_address.is_empty() {
+            continue;
+        }
+
+        let is_reverted = reverted.iter().any(|(tr_hash, call_path)| {
+            *tr_hash == trace.transaction_hash && trace.trace_address.starts_with(call_path)
+        });
+        if is_reverted {
+            continue;
+        }
+
+        let action = &trace.action;
+        let transfer = match trace.trace_type.as_str() {
+            // Value of `delegatecall` stays in the calling contract
+            "call" if action.call_type.as_deref() == Some("call") => {
+                action.from.zip(action.to).zip(action.value)
+            }
+            "create" => action
+                .from
+                .zip(trace.result.as_ref().and_then(|v| v.address))
+                .zip(action.value),
+            "suicide" => action
+                .address
+                .zip(action.refund_address)
+                .zip(action.balance),
+            _ => None,
+        };
+
+        let ((from, to), value) = match transfer {
+            Some(transfer) if !transfer.1.is_zero() => transfer,
+            _ => continue,
+        };
+
+        let transaction_index = trace.transaction_position.unwrap_or_default();
+        let transaction_hash = match trace.transaction_hash {
+            Some(tr_hash) => tr_hash,
+            None => transaction_hash(block, transaction_index as usize)?,
+        };
+
+        let call_type = match trace.trace_type.as_str() {
+            "suicide" => "selfdestruct",
+            call_type => call_type,
+        };
+
+        internal_transactions.push(InternalTransaction {
+            block_number: block.number.unwrap_or_default(),
+            timestamp: block.timestamp.as_u64(),
+            transaction_hash,
+            transaction_index,
+            call_path: trace.trace_address.clone(),
+            call_type: call_type.to_string(),
+            from,
+            to,
+            value,
+        });
+    }
+
+    internal_transactions.sort_by(|a, b| {
+        (a.transaction_index, &a.call_path).cmp(&(b.transaction_index, &b.call_path))
+    });
+
+    Ok(internal_transactions)
+}
+
+/// Result: internal transactions of `debug_traceBlockByNumber` response `traces` of `block`,
+/// sorted by transaction index and call path.
+/// Calls of reverted transactions and subcalls of reverted calls are skipped,
+/// as well as transactions which failed to be traced
+pub fn from_geth_traces(
+    block: &Block<Transaction>,
+    traces: Value,
+) -> Result<Vec<InternalTransaction>, Box<dyn std::error::Error>> {
+    let traces: Vec<GethTransactionTrace> = serde_json::from_value(traces)?;
+
+    let mut internal_transactions = Vec::new();
+
+    // Traces are in the order of block transactions
+    for (transaction_index, trace) in traces.iter().enumerate() {
+        let transaction_hash = match trace.tx_hash {
+            Some(tr_hash) => tr_hash,
+            None => transaction_hash(block, transaction_index)?,
+        };
+
+        let result = match &trace.result {
+            Some(result) => result,
+            None => {
+                warn!(
+                    "Trace of transaction {:?} failed: {}. Its internal transactions are skipped.",
+                    transaction_hash,
+                    trace.error.as_deref().unwrap_or("no result"),
+                );
+
+                continue;
+            }
+        };
+        if result.error.is_some() {
+            continue;
+        }
+
+        // Stack of (call path, call frame). The transaction call itself isn't internal
+        let mut frames: Vec<(Vec<usize>, &GethCallFrame)> = result
+            .calls
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| (vec![i], frame))
+            .collect();
+
+        while let Some((call_path, frame)) = frames.pop() {
+            if frame.error.is_some() {
+                continue;
+            }
+
+            for (i, subframe) in frame.calls.iter().enumerate() {
+                let mut subcall_path = call_path.clone();
+                subcall_path.push(i);
+
+                frames.push((subcall_path, subframe));
+            }
+
+            // Value of `DELEGATECALL` and `CALLCODE` stays in the calling contract
+            let call_type = frame.call_type.to_lowercase();
+            if !matches!(
+                call_type.as_str(),
+                "call" | "create" | "create2" | "selfdestruct",
+            ) {
+                continue;
+            }
+
+            let (to, value) = match frame.to.zip(frame.value) {
+                Some((to, value)) if !value.is_zero() => (to, value),
+                _ => continue,
+            };
+
+            internal_transactions.push(InternalTransaction {
+                block_number: block.number.unwrap_or_default(),
+                timestamp: block.timestamp.as_u64(),
+                transaction_hash,
+                transaction_index: transaction_index as u64,
+                call_path,
+                call_type,
+                from: frame.from,
+                to,
+                value,
+            });
+        }
+    }
+
+    internal_transactions.sort_by(|a, b| {
+        (a.transaction_index, &a.call_path).cmp(&(b.transaction_index, &b.call_path))
+    });
+
+    Ok(internal_transactions)
+}
+
+fn transaction_hash(
+    block: &Block<Transaction>,
+    transaction_index: usize,
+) -> Result<H256, Box<dyn std::error::Error>> {
+    let transaction = block
+        .transactions
+        .get(transaction_index)
+        .ok_or("Trace of unknown transaction")?;
+
+    Ok(transaction.hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn address(n: u64) -> Address {
+        Address::from_low_u64_be(n)
+    }
+
+    fn tr_hash(transaction_index: u64) -> H256 {
+        H256::from_low_u64_be(0x100 + transaction_index)
+    }
+
+    fn transaction(transaction_index: u64) -> Value {
+        json!({
+            "hash": tr_hash(transaction_index),
+            "nonce": "0x0",
+            "blockHash": H256::repeat_byte(0xbb),
+            "blockNumber": "0x64",
+            "transactionIndex": U64::from(transaction_index),
+            "from": address(1),
+            "to": address(10),
+            "value": "0x0",
+            "gasPrice": "0x1",
+            "gas": "0x5208",
+            "input": "0x",
+        })
+    }
+
+    fn block() -> Block<Transaction> {
+        serde_json::from_value(json!({
+            "hash": H256::repeat_byte(0xbb),
+            "parentHash": H256::zero(),
+            "sha3Uncles": H256::zero(),
+            "miner": Address::zero(),
+            "stateRoot": H256::zero(),
+            "transactionsRoot": H256::zero(),
+            "receiptsRoot": H256::zero(),
+            "number": "0x64",
+            "gasUsed": "0x0",
+            "gasLimit": "0x0",
+            "extraData": "0x",
+            "logsBloom": null,
+            "timestamp": "0x5f5e1000",
+            "difficulty": "0x0",
+            "uncles": [],
+            "transactions": [transaction(0), transaction(1)],
+            "size": null,
+            "mixHash": null,
+            "nonce": null,
+        }))
+        .unwrap()
+    }
+
+    fn parity_call(
+        transaction_index: u64,
+        trace_address: &[usize],
+        call_type: &str,
+        (from, to, value): (u64, u64, u64),
+        error: Option<&str>,
+    ) -> Value {
+        json!({
+            "action": {
+                "from": address(from),
+                "to": address(to),
+                "value": U256::from(value),
+                "callType": call_type,
+                "gas": "0x0",
+                "input": "0x",
+            },
+            "result": if error.is_some() { Value::Null } else { json!({"gasUsed": "0x0", "output": "0x"}) },
+            "error": error,
+            "traceAddress": trace_address,
+            "transactionHash": tr_hash(transaction_index),
+            "transactionPosition": transaction_index,
+            "subtraces": 0,
+            "type": "call",
+        })
+    }
+
+    /// Calls of transaction 0 from contract 10:
+    /// `[0]` call to 11 with 1 wei,
+    /// `[1]` reverted call to 12 with 2 wei and its subcall `[1, 0]` from 12 to 13 with 3 wei,
+    /// `[2]` delegatecall to 14 with 4 wei and its subcall `[2, 0]` to 15 with 5 wei,
+    /// `[3]` selfdestruct to 16 of 6 wei, `[4]` create of 17 with 7 wei.
+    /// Transaction 1 is reverted, its call `[0]` to 18 with 8 wei is reverted too
+    fn parity_traces() -> Value {
+        json!([
+            parity_call(0, &[], "call", (1, 10, 0), None),
+            parity_call(0, &[0], "call", (10, 11, 1), None),
+            parity_call(0, &[1], "call", (10, 12, 2), Some("Reverted")),
+            parity_call(0, &[1, 0], "call", (12, 13, 3), None),
+            parity_call(0, &[2], "delegatecall", (10, 14, 4), None),
+            parity_call(0, &[2, 0], "call", (10, 15, 5), None),
+            {
+                "action": {
+                    "address": address(10),
+                    "refundAddress": address(16),
+                    "balance": U256::from(6),
+                },
+                "result": null,
+                "traceAddress": [3],
+                "transactionHash": tr_hash(0),
+                "transactionPosition": 0,
+                "subtraces": 0,
+                "type": "suicide",
+            },
+            {
+                "action": {
+                    "from": address(10),
+                    "value": U256::from(7),
+                    "gas": "0x0",
+                    "init": "0x",
+                },
+                "result": {"address": address(17), "code": "0x", "gasUsed": "0x0"},
+                "traceAddress": [4],
+                "transactionHash": tr_hash(0),
+                "transactionPosition": 0,
+                "subtraces": 0,
+                "type": "create",
+            },
+            parity_call(1, &[], "call", (1, 10, 0), Some("Reverted")),
+            parity_call(1, &[0], "call", (10, 18, 8), None),
+        ])
+    }
+
+    fn geth_frame(
+        call_type: &str,
+        (from, to, value): (u64, u64, u64),
+        error: Option<&str>,
+        calls: Vec<Value>,
+    ) -> Value {
+        json!({
+            "type": call_type,
+            "from": address(from),
+            "to": address(to),
+            "value": U256::from(value),
+            "gas": "0x0",
+            "gasUsed": "0x0",
+            "input": "0x",
+            "error": error,
+            "calls": calls,
+        })
+    }
+
+    /// Same calls as of `parity_traces`
+    fn geth_traces() -> Value {
+        json!([
+            {
+                "txHash": tr_hash(0),
+                "result": geth_frame("CALL", (1, 10, 0), None, vec![
+                    geth_frame("CALL", (10, 11, 1), None, vec![]),
+                    geth_frame("CALL", (10, 12, 2), Some("execution reverted"), vec![
+                        geth_frame("CALL", (12, 13, 3), None, vec![]),
+                    ]),
+                    geth_frame("DELEGATECALL", (10, 14, 4), None, vec![
+                        geth_frame("CALL", (10, 15, 5), None, vec![]),
+                    ]),
+                    geth_frame("SELFDESTRUCT", (10, 16, 6), None, vec![]),
+                    geth_frame("CREATE", (10, 17, 7), None, vec![]),
+                ]),
+            },
+            {
+                "result": geth_frame("CALL", (1, 10, 0), Some("execution reverted"), vec![
+                    geth_frame("CALL", (10, 18, 8), None, vec![]),
+                ]),
+            },
+        ])
+    }
+
+    /// Result: (call path, call type, to, value)
+    fn calls(
+        internal_transactions: &[InternalTransaction],
+    ) -> Vec<(Vec<usize>, &str, Address, U256)> {
+        internal_transactions
+            .iter()
+            .map(|v| (v.call_path.clone(), v.call_type.as_str(), v.to, v.value))
+            .collect()
+    }
+
+    fn expected_calls() -> Vec<(Vec<usize>, &'static str, Address, U256)> {
+        vec![
+            (vec![0], "call", address(11), U256::from(1)),
+            (vec![2, 0], "call", address(15), U256::from(5)),
+            (vec![3], "selfdestruct", address(16), U256::from(6)),
+            (vec![4], "create", address(17), U256::from(7)),
+        ]
+    }
+
+    #[test]
+    fn parity_reverted_and_delegate_calls_are_skipped() {
+        let internal_transactions = from_parity_traces(&block(), parity_traces()).unwrap();
+
+        assert_eq!(calls(&internal_transactions), expected_calls());
+        assert!(internal_transactions
+            .iter()
+            .all(|v| v.transaction_hash == tr_hash(0) && v.from == address(10)));
+    }
+
+    #[test]
+    fn geth_reverted_and_delegate_calls_are_skipped() {
+        let internal_transactions = from_geth_traces(&block(), geth_traces()).unwrap();
+
+        assert_eq!(calls(&internal_transactions), expected_calls());
+        assert!(internal_transactions
+            .iter()
+            .all(|v| v.transaction_hash == tr_hash(0) && v.from == address(10)));
+    }
+
+    #[test]
+    fn parity_and_geth_call_paths_are_the_same() {
+        let parity = from_parity_traces(&block(), parity_traces()).unwrap();
+        let geth = from_geth_traces(&block(), geth_traces()).unwrap();
+
+        assert_eq!(calls(&parity), calls(&geth));
+    }
+
+    #[test]
+    fn parity_reverted_call_prefix_does_not_skip_sibling_subtree() {
+        // `[1]` is reverted, `[10]` only shares the first digit of its string view
+        let traces = json!([
+            parity_call(0, &[1], "call", (10, 12, 2), Some("Reverted")),
+            parity_call(0, &[10], "call", (10, 11, 1), None),
+        ]);
+
+        let internal_transactions = from_parity_traces(&block(), traces).unwrap();
+
+        assert_eq!(
+            calls(&internal_transactions),
+            vec![(vec![10], "call", address(11), U256::from(1))]
+        );
+    }
+
+    #[test]
+    fn geth_transaction_without_hash_takes_it_from_block() {
+        let traces = json!([
+            {"txHash": tr_hash(0), "result": geth_frame("CALL", (1, 10, 0), None, vec![])},
+            {"result": geth_frame("CALL", (1, 10, 0), None, vec![
+                geth_frame("CALL", (10, 11, 1), None, vec![]),
+            ])},
+        ]);
+
+        let internal_transactions = from_geth_traces(&block(), traces).unwrap();
+
+        assert_eq!(internal_transactions.len(), 1);
+        assert_eq!(internal_transactions[0].transaction_hash, tr_hash(1));
+        assert_eq!(internal_transactions[0].transaction_index, 1);
+    }
+
+    #[test]
+    fn geth_failed_transaction_trace_is_skipped() {
+        let traces = json!([
+            {"txHash": tr_hash(0), "error": "execution timeout"},
+            {"txHash": tr_hash(1), "result": geth_frame("CALL", (1, 10, 0), None, vec![
+                geth_frame("CALL", (10, 11, 1), None, vec![]),
+            ])},
+        ]);
+
+        let internal_transactions = from_geth_traces(&block(), traces).unwrap();
+
+        assert_eq!(
+            calls(&internal_transactions),
+            vec![(vec![0], "call", address(11), U256::from(1))]
+        );
+        assert_eq!(internal_transactions[0].transaction_index, 1);
+    }
+}
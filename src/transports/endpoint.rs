@@ -0,0 +1,113 @@
+use crate::transports::http::Http;
+use futures::future::{self, BoxFuture};
+use jsonrpc_core::types::{Call, Value};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time;
+use web3::error::TransportError;
+use web3::transports::{Ipc, WebSocket};
+use web3::{BatchTransport, RequestId, Transport};
+
+/// Connection to one JSON RPC server. Transport is selected by the url scheme:
+/// `http(s)://` - HTTP, `ws(s)://` - WebSocket, anything else - path of IPC socket
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    Http(Http),
+    /// Request timeout is applied here, HTTP transport applies it by itself
+    WebSocket(WebSocket, Duration),
+    Ipc(Ipc, Duration),
+}
+
+impl Endpoint {
+    /// Param `timeout` - timeout of every request
+    pub async fn connect(url: &str, timeout: Duration) -> web3::Result<Self> {
+        let endpoint = if url.starts_with("http://") || url.starts_with("https://") {
+            Self::Http(Http::new(url, timeout)?)
+        } else if url.starts_with("ws://") || url.starts_with("wss://") {
+            let transport = with_timeout(WebSocket::new(url), timeout).await?;
+
+            Self::WebSocket(transport, timeout)
+        } else {
+            let transport = with_timeout(Ipc::new(url), timeout).await?;
+
+            Self::Ipc(transport, timeout)
+        };
+
+        Ok(endpoint)
+    }
+}
+
+async fn with_timeout<T, F>(request: F, timeout: Duration) -> web3::Result<T>
+where
+    F: Future<Output = web3::Result<T>>,
+{
+    time::timeout(timeout, request).await.map_err(|_| {
+        web3::Error::Transport(TransportError::Message(format!(
+            "Request timed out after {:?}",
+            timeout
+        )))
+    })?
+}
+
+impl Transport for Endpoint {
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        match self {
+            Self::Http(transport) => transport.prepare(method, params),
+            Self::WebSocket(transport, _) => transport.prepare(method, params),
+            Self::Ipc(transport, _) => transport.prepare(method, params),
+        }
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        match self {
+            Self::Http(transport) => transport.send(id, request),
+            Self::WebSocket(transport, timeout) => {
+                Box::pin(with_timeout(transport.send(id, request), *timeout))
+            }
+            Self::Ipc(transport, timeout) => {
+                Box::pin(with_timeout(transport.send(id, request), *timeout))
+            }
+        }
+    }
+}
+
+impl BatchTransport for Endpoint {
+    type Batch = BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>>;
+
+    fn send_batch<T>(&self, requests: T) -> Self::Batch
+    where
+        T: IntoIterator<Item = (RequestId, Call)>,
+    {
+        match self {
+            Self::Http(transport) => transport.send_batch(requests),
+            // WebSocket transport of web3 matches the batch response by its first id only
+            // and returns results in the response order, so requests are sent one by one
+            // and every response is matched by its own id
+            Self::WebSocket(transport, timeout) => {
+                let requests: Vec<_> = requests
+                    .into_iter()
+                    .map(|(id, request)| with_timeout(transport.send(id, request), *timeout))
+                    .collect();
+
+                Box::pin(async move {
+                    let mut results = future::join_all(requests).await;
+
+                    // Failed connection fails the whole batch, as it does over HTTP
+                    if let Some(index) = results.iter().position(
+                        |result| matches!(result, Err(e) if !matches!(e, web3::Error::Rpc(_))),
+                    ) {
+                        return Err(results.swap_remove(index).unwrap_err());
+                    }
+
+                    Ok(results)
+                })
+            }
+            // IPC transport of web3 matches every response by its id
+            Self::Ipc(transport, timeout) => {
+                Box::pin(with_timeout(transport.send_batch(requests), *timeout))
+            }
+        }
+    }
+}
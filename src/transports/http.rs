@@ -0,0 +1,192 @@
+use futures::future::BoxFuture;
+use jsonrpc_core::types::{Call, Output, Request, Value};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::{self, Instant};
+use web3::error::{Error, TransportError};
+use web3::{helpers, BatchTransport, RequestId, Transport};
+
+/// JSON RPC over HTTP. Same as `web3::transports::Http`, but every request has a timeout,
+/// and after a rate limited response (HTTP 429 or 503) with `Retry-After` header
+/// no requests are sent until the moment from the header
+#[derive(Clone, Debug)]
+pub struct Http {
+    client: Client,
+    url: Url,
+    timeout: Duration,
+    id: Arc<AtomicUsize>,
+    /// Requests are not sent before this moment
+    blocked_until: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Http {
+    pub fn new(url: &str, timeout: Duration) -> web3::Result<Self> {
+        let client = Client::builder().build().map_err(|e| {
+            Error::Transport(TransportError::Message(format!(
+                "Failed to build client: {}",
+                e
+            )))
+        })?;
+
+        Ok(Self {
+            client,
+            url: url.parse()?,
+            timeout,
+            id: Arc::new(AtomicUsize::new(0)),
+            blocked_until: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    fn next_id(&self) -> RequestId {
+        self.id.fetch_add(1, Ordering::AcqRel)
+    }
+
+    async fn execute_rpc<T: DeserializeOwned>(self, request: Request) -> web3::Result<T> {
+        let blocked_until = *self.blocked_until.lock().unwrap();
+        if let Some(blocked_until) = blocked_until {
+            time::sleep_until(blocked_until).await;
+        }
+
+        let response = self
+            .client
+            .post(self.url.clone())
+            .json(&request)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(|e| {
+                let message = if e.is_timeout() {
+                    format!("Request timed out after {:?}", self.timeout)
+                } else {
+                    format!("Failed to send request: {}", e)
+                };
+
+                Error::Transport(TransportError::Message(message))
+            })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+            {
+                self.block_by_retry_after(&response);
+            }
+
+            return Err(Error::Transport(TransportError::Code(status.as_u16())));
+        }
+
+        let response = response.bytes().await.map_err(|e| {
+            Error::Transport(TransportError::Message(format!(
+                "Failed to read response: {}",
+                e
+            )))
+        })?;
+
+        helpers::arbitrary_precision_deserialize_workaround(&response).map_err(|e| {
+            Error::Transport(TransportError::Message(format!(
+                "Failed to deserialize response: {}",
+                e
+            )))
+        })
+    }
+
+    /// Delays next requests by `Retry-After` header (seconds or HTTP date) of `response`
+    fn block_by_retry_after(&self, response: &Response) {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| match v.trim().parse::<u64>() {
+                Ok(seconds) => Some(Duration::from_secs(seconds)),
+                Err(_) => httpdate::parse_http_date(v)
+                    .ok()
+                    .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default()),
+            });
+
+        if let Some(retry_after) = retry_after {
+            warn!(
+                "Server is rate limited. Requests are paused for {:?}.",
+                retry_after
+            );
+
+            let until = Instant::now() + retry_after;
+
+            let mut blocked_until = self.blocked_until.lock().unwrap();
+            if blocked_until.is_none_or(|v| v < until) {
+                *blocked_until = Some(until);
+            }
+        }
+    }
+}
+
+impl Transport for Http {
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        let id = self.next_id();
+        let request = helpers::build_request(id, method, params);
+
+        (id, request)
+    }
+
+    fn send(&self, _id: RequestId, request: Call) -> Self::Out {
+        let transport = self.clone();
+
+        Box::pin(async move {
+            let output: Output = transport.execute_rpc(Request::Single(request)).await?;
+
+            helpers::to_result_from_output(output)
+        })
+    }
+}
+
+impl BatchTransport for Http {
+    type Batch = BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>>;
+
+    fn send_batch<T>(&self, requests: T) -> Self::Batch
+    where
+        T: IntoIterator<Item = (RequestId, Call)>,
+    {
+        let transport = self.clone();
+        let (ids, requests): (Vec<_>, Vec<_>) = requests.into_iter().unzip();
+
+        Box::pin(async move {
+            let outputs: Vec<Output> = transport.execute_rpc(Request::Batch(requests)).await?;
+
+            // Server may return batch responses in any order
+            if ids.len() != outputs.len() {
+                Err(Error::InvalidResponse(
+                    "Unexpected number of responses".to_string(),
+                ))?;
+            }
+
+            let mut outputs = outputs
+                .into_iter()
+                .map(|output| {
+                    let id = match &output {
+                        Output::Success(success) => &success.id,
+                        Output::Failure(failure) => &failure.id,
+                    };
+                    let id = match id {
+                        jsonrpc_core::Id::Num(id) => *id as RequestId,
+                        _ => Err(Error::InvalidResponse("Response id is not u64".to_string()))?,
+                    };
+
+                    Ok((id, helpers::to_result_from_output(output)))
+                })
+                .collect::<web3::Result<HashMap<_, _>>>()?;
+
+            ids.iter()
+                .map(|id| {
+                    outputs.remove(id).ok_or_else(|| {
+                        Error::InvalidResponse(format!("Batch response is missing id {}", id))
+                    })
+                })
+                .collect()
+        })
+    }
+}
@@ -0,0 +1,4 @@
+pub mod endpoint;
+pub mod http;
+pub mod pool;
+pub mod quorum;
@@ -0,0 +1,497 @@
+use crate::rpc::RetryPolicy;
+use crate::transports::endpoint::Endpoint;
+use crate::transports::quorum;
+use futures::future::{self, BoxFuture};
+use jsonrpc_core::types::{Call, Value};
+use jsonrpc_core::{MethodCall, Params};
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use web3::{helpers, BatchTransport, RequestId, Transport};
+
+/// Provider which is more than this number of blocks behind the best head is not used
+pub const MAX_HEAD_LAG: u64 = 10;
+const HEAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// JSON RPC server, its limits and the results of its health checks
+#[derive(Debug)]
+pub struct Provider {
+    /// Host of the server url. Full url may contain an API key, so it isn't logged
+    name: String,
+    transport: Endpoint,
+    /// Share of requests relative to other providers
+    weight: u32,
+    /// Max number of in-flight requests
+    concurrency: usize,
+    limiter: Arc<Semaphore>,
+    healthy: AtomicBool,
+    head: AtomicU64,
+    /// Number of requests served since the last `take_served` call
+    served: AtomicU64,
+    /// Methods which the server doesn't implement, e.g. `eth_getBlockReceipts`
+    unsupported_methods: Mutex<HashSet<String>>,
+}
+
+impl Provider {
+    pub fn new(
+        name: String,
+        transport: Endpoint,
+        weight: u32,
+        concurrency: usize,
+        healthy: bool,
+        head: u64,
+    ) -> Self {
+        Self {
+            name,
+            transport,
+            weight,
+            concurrency,
+            limiter: Arc::new(Semaphore::new(concurrency)),
+            healthy: AtomicBool::new(healthy),
+            head: AtomicU64::new(head),
+            served: AtomicU64::new(0),
+            unsupported_methods: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn supports(&self, method: &str) -> bool {
+        !self.unsupported_methods.lock().unwrap().contains(method)
+    }
+
+    fn set_unsupported(&self, method: &str) {
+        if self
+            .unsupported_methods
+            .lock()
+            .unwrap()
+            .insert(method.to_string())
+        {
+            info!(
+                "Provider {} doesn't support {}. Its requests go to other providers.",
+                self.name, method,
+            );
+        }
+    }
+
+    /// Result: in-flight requests per unit of weight
+    fn load(&self) -> f64 {
+        let in_flight = self.concurrency - self.limiter.available_permits();
+
+        in_flight as f64 / self.weight as f64
+    }
+}
+
+/// Transport which spreads requests across several JSON RPC providers
+pub trait ProviderStats {
+    /// Result: max number of in-flight requests of all providers
+    fn capacity(&self) -> usize;
+
+    /// Result: (provider name, number of requests) of every provider which served requests
+    /// since the previous call
+    fn take_served(&self) -> Vec<(String, u64)>;
+}
+
+/// Spreads requests across all healthy providers by their weights and concurrency limits.
+/// Request failed by a provider is reassigned to another one,
+/// and the provider isn't used until the next head check finds it healthy
+#[derive(Clone, Debug)]
+pub struct ProviderPool {
+    providers: Arc<Vec<Provider>>,
+    /// Number of providers which must return the same blocks, transactions and receipts.
+    /// `1` means no verification
+    quorum: usize,
+    id: Arc<AtomicUsize>,
+}
+
+impl ProviderPool {
+    /// Param `quorum` - not greater than the number of `providers`
+    pub fn new(providers: Vec<Provider>, quorum: usize) -> Self {
+        let names: Vec<String> = providers
+            .iter()
+            .map(|provider| {
+                format!(
+                    "{} (weight {}, concurrency {})",
+                    provider.name, provider.weight, provider.concurrency,
+                )
+            })
+            .collect();
+        info!("Using providers: {}.", names.join(", "));
+
+        if quorum > 1 {
+            info!("Responses are verified by quorum of {} providers.", quorum);
+        }
+
+        Self {
+            providers: Arc::new(providers),
+            quorum,
+            id: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Checks heads of all providers every `HEAD_CHECK_INTERVAL`.
+    /// Lagging and failed providers become unhealthy, caught up ones become healthy again
+    pub fn watch_heads(&self) {
+        let pool = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HEAD_CHECK_INTERVAL).await;
+
+                pool.check_heads().await;
+            }
+        });
+    }
+
+    async fn check_heads(&self) {
+        let heads = future::join_all(self.providers.iter().map(|provider| {
+            let transport = provider.transport.clone();
+
+            async move {
+                let head = transport.execute("eth_blockNumber", vec![]).await.ok()?;
+
+                serde_json::from_value::<web3::types::U64>(head).ok()
+            }
+        }))
+        .await;
+
+        let best_head = heads.iter().flatten().max().cloned().unwrap_or_default();
+
+        for (provider, head) in self.providers.iter().zip(heads) {
+            let healthy = match head {
+                Some(head) => {
+                    provider.head.store(head.as_u64(), Ordering::Release);
+
+                    head.as_u64() + MAX_HEAD_LAG >= best_head.as_u64()
+                }
+                None => false,
+            };
+
+            let was_healthy = provider.healthy.swap(healthy, Ordering::AcqRel);
+            if was_healthy != healthy {
+                info!(
+                    "Provider {} is {}. Head: {}.",
+                    provider.name,
+                    if healthy { "healthy" } else { "unhealthy" },
+                    provider.head.load(Ordering::Acquire),
+                );
+            }
+        }
+    }
+
+    /// Result: the least loaded provider which is not `tried` yet. Healthy ones go first,
+    /// but when none of them is left, unhealthy ones are tried anyway
+    fn choose(&self, tried: &[usize]) -> Option<usize> {
+        (0..self.providers.len())
+            .filter(|i| !tried.contains(i))
+            .min_by(|a, b| {
+                let a = &self.providers[*a];
+                let b = &self.providers[*b];
+
+                let a_healthy = a.healthy.load(Ordering::Acquire);
+                let b_healthy = b.healthy.load(Ordering::Acquire);
+
+                b_healthy
+                    .cmp(&a_healthy)
+                    .then(a.load().total_cmp(&b.load()))
+            })
+    }
+
+    /// Result: method of `request` if its response is verified by quorum
+    fn quorum_method(&self, request: &Call) -> Option<String> {
+        match request {
+            Call::MethodCall(MethodCall {
+                method,
+                params: Params::Array(params),
+                ..
+            }) if self.quorum > 1 && quorum::is_verified(method, params) => Some(method.clone()),
+            _ => None,
+        }
+    }
+
+    /// Result: indexes of providers which don't support `method`
+    fn unsupported(&self, method: &str) -> Vec<usize> {
+        (0..self.providers.len())
+            .filter(|i| !self.providers[*i].supports(method))
+            .collect()
+    }
+
+    /// Sends `request` to `quorum` least loaded providers which support `method`
+    /// and compares `digest` of their responses.
+    /// Disagreement is an error, so that the response is retried and never saved
+    async fn send_to_quorum<T, F, Fut, D>(
+        self,
+        method: String,
+        request: F,
+        digest: D,
+    ) -> web3::Result<T>
+    where
+        F: Fn(Endpoint) -> Fut,
+        Fut: Future<Output = web3::Result<T>>,
+        D: Fn(&T) -> Value,
+    {
+        let unsupported = self.unsupported(&method);
+        if self.providers.len() - unsupported.len() < self.quorum {
+            return Err(method_not_found());
+        }
+
+        let mut indexes = Vec::with_capacity(self.quorum);
+        while indexes.len() < self.quorum {
+            let excluded = [indexes.as_slice(), unsupported.as_slice()].concat();
+            indexes.push(self.choose(&excluded).ok_or(web3::Error::Unreachable)?);
+        }
+
+        let results = future::join_all(indexes.iter().map(|index| {
+            let provider = &self.providers[*index];
+            let request = request(provider.transport.clone());
+
+            async move {
+                let _permit = provider
+                    .limiter
+                    .acquire()
+                    .await
+                    .map_err(|_| web3::Error::Internal)?;
+
+                request.await
+            }
+        }))
+        .await;
+
+        let mut responses = Vec::with_capacity(results.len());
+        for (index, result) in indexes.iter().zip(results) {
+            let provider = &self.providers[*index];
+            provider.served.fetch_add(1, Ordering::AcqRel);
+
+            match result {
+                Ok(response) => responses.push((provider, response)),
+                // Response of another provider is retried
+                Err(e) if RetryPolicy::is_method_not_found(&e) => {
+                    provider.set_unsupported(&method);
+
+                    return Err(web3::Error::InvalidResponse(format!(
+                        "{} is not supported by provider {}",
+                        method, provider.name,
+                    )));
+                }
+                Err(e) => {
+                    if RetryPolicy::is_temporary(&e)
+                        && provider.healthy.swap(false, Ordering::AcqRel)
+                    {
+                        warn!("Provider {} failed: {}.", provider.name, e);
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+
+        let digests: Vec<Value> = responses
+            .iter()
+            .map(|(_, response)| digest(response))
+            .collect();
+
+        if digests.iter().any(|v| *v != digests[0]) {
+            let details: Vec<String> = responses
+                .iter()
+                .zip(&digests)
+                .map(|((provider, _), digest)| {
+                    let digest = digest.to_string();
+                    let digest: String = digest.chars().take(200).collect();
+
+                    format!("{}: {}", provider.name, digest)
+                })
+                .collect();
+            warn!("Quorum disagreement on {}. {}", method, details.join(". "),);
+
+            return Err(web3::Error::InvalidResponse(format!(
+                "Quorum disagreement on {}",
+                method,
+            )));
+        }
+
+        Ok(responses.swap_remove(0).1)
+    }
+
+    /// Sends `request` of `method` to the least loaded provider which supports `method`.
+    /// On a temporary error (see `RetryPolicy::is_temporary`) the provider becomes unhealthy,
+    /// and `request` is sent to the next provider until every provider is tried once.
+    /// Provider whose result `is_unsupported` isn't sent `method` anymore
+    async fn send_with<T, F, Fut>(
+        self,
+        method: Option<String>,
+        request: F,
+        is_unsupported: fn(&web3::Result<T>) -> bool,
+    ) -> web3::Result<T>
+    where
+        F: Fn(Endpoint) -> Fut,
+        Fut: Future<Output = web3::Result<T>>,
+    {
+        let mut tried = match &method {
+            Some(method) => self.unsupported(method),
+            None => Vec::new(),
+        };
+        if tried.len() == self.providers.len() {
+            return Err(method_not_found());
+        }
+
+        loop {
+            let index = self.choose(&tried).ok_or(web3::Error::Unreachable)?;
+            let provider = &self.providers[index];
+
+            let result = {
+                let _permit = provider
+                    .limiter
+                    .acquire()
+                    .await
+                    .map_err(|_| web3::Error::Internal)?;
+
+                request(provider.transport.clone()).await
+            };
+
+            provider.served.fetch_add(1, Ordering::AcqRel);
+
+            match result {
+                result if is_unsupported(&result) => {
+                    if let Some(method) = &method {
+                        provider.set_unsupported(method);
+                    }
+
+                    tried.push(index);
+                    if tried.len() == self.providers.len() {
+                        return result;
+                    }
+                }
+                Err(e) if RetryPolicy::is_temporary(&e) => {
+                    if provider.healthy.swap(false, Ordering::AcqRel) {
+                        warn!(
+                            "Provider {} failed: {}. Its requests go to other providers.",
+                            provider.name, e,
+                        );
+                    }
+
+                    tried.push(index);
+                    if tried.len() == self.providers.len() {
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl ProviderStats for ProviderPool {
+    fn capacity(&self) -> usize {
+        self.providers
+            .iter()
+            .map(|provider| provider.concurrency)
+            .sum()
+    }
+
+    fn take_served(&self) -> Vec<(String, u64)> {
+        self.providers
+            .iter()
+            .map(|provider| {
+                (
+                    provider.name.clone(),
+                    provider.served.swap(0, Ordering::AcqRel),
+                )
+            })
+            .filter(|(_, served)| *served > 0)
+            .collect()
+    }
+}
+
+impl Transport for ProviderPool {
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        let id = self.id.fetch_add(1, Ordering::AcqRel);
+        let request = helpers::build_request(id, method, params);
+
+        (id, request)
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        if let Some(method) = self.quorum_method(&request) {
+            let digest_method = method.clone();
+
+            return Box::pin(self.clone().send_to_quorum(
+                method,
+                move |transport| transport.send(id, request.clone()),
+                move |response| quorum::digest(&digest_method, response).unwrap_or_default(),
+            ));
+        }
+
+        Box::pin(self.clone().send_with(
+            call_method(&request),
+            move |transport| transport.send(id, request.clone()),
+            |result| matches!(result, Err(e) if RetryPolicy::is_method_not_found(e)),
+        ))
+    }
+}
+
+impl BatchTransport for ProviderPool {
+    type Batch = BoxFuture<'static, web3::Result<Vec<web3::Result<Value>>>>;
+
+    fn send_batch<T>(&self, requests: T) -> Self::Batch
+    where
+        T: IntoIterator<Item = (RequestId, Call)>,
+    {
+        let requests: Vec<_> = requests.into_iter().collect();
+
+        // Batch requests are of one method
+        if let Some(method) = requests
+            .first()
+            .and_then(|(_, request)| self.quorum_method(request))
+        {
+            let digest_method = method.clone();
+
+            return Box::pin(self.clone().send_to_quorum(
+                method,
+                move |transport| transport.send_batch(requests.clone()),
+                move |responses| {
+                    responses
+                        .iter()
+                        .map(|response| match response {
+                            Ok(response) => {
+                                quorum::digest(&digest_method, response).unwrap_or_default()
+                            }
+                            Err(_) => Value::from("error"),
+                        })
+                        .collect()
+                },
+            ));
+        }
+
+        Box::pin(self.clone().send_with(
+            requests
+                .first()
+                .and_then(|(_, request)| call_method(request)),
+            move |transport| transport.send_batch(requests.clone()),
+            // Batch requests are of one method
+            |result| match result {
+                Ok(results) => {
+                    !results.is_empty()
+                        && results.iter().all(
+                            |result| matches!(result, Err(e) if RetryPolicy::is_method_not_found(e)),
+                        )
+                }
+                Err(e) => RetryPolicy::is_method_not_found(e),
+            },
+        ))
+    }
+}
+
+fn call_method(request: &Call) -> Option<String> {
+    match request {
+        Call::MethodCall(MethodCall { method, .. }) => Some(method.clone()),
+        _ => None,
+    }
+}
+
+/// Error of a method which none of the providers supports
+fn method_not_found() -> web3::Error {
+    web3::Error::Rpc(jsonrpc_core::Error::method_not_found())
+}
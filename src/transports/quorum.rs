@@ -0,0 +1,73 @@
+use serde_json::{json, Value};
+
+/// Methods whose responses are compared between providers
+const VERIFIED_METHODS: [&str; 5] = [
+    "eth_getBlockByNumber",
+    "eth_getBlockByHash",
+    "eth_getTransactionByHash",
+    "eth_getTransactionReceipt",
+    "eth_getBlockReceipts",
+];
+
+/// Requests of block tags (`latest`, `safe`, etc.) are not verified,
+/// because providers may be at different heads
+pub fn is_verified(method: &str, params: &[Value]) -> bool {
+    let is_block_tag = matches!(
+        params.first().and_then(Value::as_str),
+        Some("latest" | "pending" | "safe" | "finalized" | "earliest"),
+    );
+
+    VERIFIED_METHODS.contains(&method) && !is_block_tag
+}
+
+/// Result: the part of `method` response that must be the same on all providers.
+/// `None` - responses of `method` are not compared
+pub fn digest(method: &str, response: &Value) -> Option<Value> {
+    if !VERIFIED_METHODS.contains(&method) {
+        return None;
+    }
+    if response.is_null() {
+        return Some(Value::Null);
+    }
+
+    let digest = match method {
+        // Transactions are either hashes or objects with hash
+        "eth_getBlockByNumber" | "eth_getBlockByHash" => json!({
+            "hash": response["hash"],
+            "transactions": response["transactions"]
+                .as_array()
+                .map(|transactions| {
+                    transactions
+                        .iter()
+                        .map(|transaction| match transaction {
+                            Value::String(_) => transaction.clone(),
+                            _ => transaction["hash"].clone(),
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default(),
+        }),
+        "eth_getTransactionByHash" => json!({
+            "hash": response["hash"],
+            "blockHash": response["blockHash"],
+        }),
+        "eth_getTransactionReceipt" => receipt_digest(response),
+        "eth_getBlockReceipts" => Value::Array(
+            response
+                .as_array()
+                .map(|receipts| receipts.iter().map(receipt_digest).collect())
+                .unwrap_or_default(),
+        ),
+        _ => return None,
+    };
+
+    Some(digest)
+}
+
+fn receipt_digest(receipt: &Value) -> Value {
+    json!({
+        "transactionHash": receipt["transactionHash"],
+        "blockHash": receipt["blockHash"],
+        "status": receipt["status"],
+    })
+}